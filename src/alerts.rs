@@ -0,0 +1,64 @@
+//! Alerts escalating as the usage approaches the cap, every tier fires once per window.
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{events, log, shaping, Config, Event, Meta, Usage};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AlertTier {
+    pub name: String,
+    /// fires once the usage reaches this percentage of `max_bytes`
+    pub percent: f64,
+    /// shell commands run when the tier fires, e.g. to page someone for the critical tier
+    #[serde(default)]
+    pub commands: Vec<String>,
+}
+
+pub fn default_tiers() -> Vec<AlertTier> {
+    [
+        ("info", 50.0),
+        ("warning", 80.0),
+        ("critical", 95.0),
+        ("cap_reached", 100.0),
+    ]
+    .into_iter()
+    .map(|(name, percent)| AlertTier {
+        name: name.to_string(),
+        percent,
+        commands: vec![],
+    })
+    .collect()
+}
+
+/// Fires the highest tier reached which didn't fire in this window yet. Lower tiers reached at
+/// the same time are skipped to not alert several times at once.
+pub fn check(meta: &mut Meta, usage: &Usage, config: &Config) {
+    if usage.max_bytes == 0 {
+        return;
+    }
+    let percent = usage.used_bytes as f64 / usage.max_bytes as f64 * 100.0;
+    let reached = config
+        .alert_tiers
+        .iter()
+        .filter(|tier| percent >= tier.percent && !meta.fired_alert_tiers.contains(&tier.name))
+        .collect::<Vec<_>>();
+    let Some(tier) = reached
+        .iter()
+        .max_by(|a, b| a.percent.total_cmp(&b.percent))
+    else {
+        return;
+    };
+    meta.fired_alert_tiers
+        .extend(reached.iter().map(|tier| tier.name.clone()));
+    log(&format!(
+        "Alert {}: {percent:.1}% of the cap used ({} of {} bytes)",
+        tier.name, usage.used_bytes, usage.max_bytes
+    ));
+    events::publish(Event::Alert {
+        tier: &tier.name,
+        used_bytes: usage.used_bytes,
+        max_bytes: usage.max_bytes,
+    });
+    shaping::run_hooks("alert", &tier.commands);
+    meta.store();
+}
@@ -0,0 +1,51 @@
+//! Detection of traffic spikes, independent of the cap, to catch sudden unexpected egress
+//! like a runaway process or a compromised host.
+
+use std::collections::VecDeque;
+
+/// sudden traffic on an otherwise idle host shouldn't count as a spike, so the baseline is
+/// at least this many bytes per second
+const MIN_BASELINE_RATE: f64 = 1024.0;
+
+/// the baseline is only trusted once it consists of this many samples
+const MIN_BASELINE_SAMPLES: usize = 6;
+
+#[derive(Default)]
+pub struct SpikeDetector {
+    /// time and smoothed rate of every check within the baseline window
+    samples: VecDeque<(u64, f64)>,
+    /// whether the current rate is above the threshold, so every spike is reported once
+    spiking: bool,
+}
+
+impl SpikeDetector {
+    /// Records `rate` and returns the baseline it exceeds `multiplier` times when a spike starts.
+    pub fn check(
+        &mut self,
+        now_ms: u64,
+        rate: f64,
+        window_ms: u64,
+        multiplier: f64,
+    ) -> Option<f64> {
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at_ms, _)| at_ms + window_ms < now_ms)
+        {
+            self.samples.pop_front();
+        }
+        let baseline = (self.samples.len() >= MIN_BASELINE_SAMPLES).then(|| {
+            self.samples.iter().map(|(_, rate)| rate).sum::<f64>() / self.samples.len() as f64
+        });
+        let spike = baseline
+            .map(|baseline| baseline.max(MIN_BASELINE_RATE))
+            .filter(|baseline| rate > baseline * multiplier);
+        let started = spike.is_some() && !self.spiking;
+        self.spiking = spike.is_some();
+        // spikes aren't added to the baseline so that a long spike doesn't become the new normal
+        if !self.spiking {
+            self.samples.push_back((now_ms, rate));
+        }
+        spike.filter(|_| started)
+    }
+}
@@ -0,0 +1,60 @@
+//! Calibration of the accounting against the usage reported by the ISP, stored as one JSON
+//! record per line. The ratio between both replaces `overhead_correction` once there is a
+//! calibration point.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{config_path, ephemeral};
+
+/// only the most recent calibration points are considered so that the factor follows changes
+/// of the ISP's metering
+const MAX_POINTS: usize = 10;
+
+#[derive(Serialize, Deserialize)]
+pub struct CalibrationPoint {
+    pub at_ms: u64,
+    /// usage of the current window according to the ISP
+    pub isp_bytes: u64,
+    /// our uncorrected count of the current window at that time
+    pub measured_bytes: u64,
+}
+
+fn path() -> PathBuf {
+    config_path("calibration.jsonl")
+}
+
+pub fn append(point: &CalibrationPoint) -> std::io::Result<()> {
+    if ephemeral() {
+        return Ok(());
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path())
+        .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(point).unwrap()))
+}
+
+/// Correction factor derived from the recent calibration points, `None` if there are none.
+pub fn factor() -> Option<f64> {
+    let points = fs::read_to_string(path())
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str::<CalibrationPoint>(line).ok())
+        .collect::<Vec<_>>();
+    let recent = &points[points.len().saturating_sub(MAX_POINTS)..];
+    let isp = recent
+        .iter()
+        .map(|point| point.isp_bytes as f64)
+        .sum::<f64>();
+    let measured = recent
+        .iter()
+        .map(|point| point.measured_bytes as f64)
+        .sum::<f64>();
+    (measured > 0.0).then(|| isp / measured)
+}
@@ -0,0 +1,309 @@
+//! The decision every check takes on the current usage, shared by the daemon and
+//! [`replay`](crate::replay) so that a replayed trace gets throttled exactly like the daemon
+//! would have.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    policy::Decision,
+    shaping::{self, Enforcement, ThrottleSplit},
+    Config, Meta, Usage,
+};
+
+/// Applies and removes the limitation, the daemon runs `tc` while a replay only pretends to.
+pub trait Shaper {
+    fn limit(
+        &mut self,
+        rate: u64,
+        rates: &BTreeMap<String, f64>,
+        config: &Config,
+    ) -> Result<(), String>;
+
+    fn release(&mut self, config: &Config);
+}
+
+/// Applies the limitation on this host.
+pub struct Tc;
+
+impl Shaper for Tc {
+    fn limit(
+        &mut self,
+        rate: u64,
+        rates: &BTreeMap<String, f64>,
+        config: &Config,
+    ) -> Result<(), String> {
+        shaping::enable_lower_bandwidth(rate, config, rates)
+    }
+
+    fn release(&mut self, config: &Config) {
+        shaping::disable_lowered_bandwidth(config);
+    }
+}
+
+/// Whether the usage exceeds the cap by more than the burst allowance or exceeds the daily cap.
+pub fn over_cap(meta: &mut Meta, usage: &Usage, now_ms: u64, config: &Config) -> bool {
+    // the bucket gets consulted under the cap as well, that's when it refills
+    !meta.absorb_burst(usage, now_ms, config)
+        || config
+            .daily_max_bytes
+            .is_some_and(|daily_max| usage.daily_used_bytes > daily_max)
+}
+
+/// Decision taken when no external policy is configured or reachable.
+pub fn built_in(
+    meta: &Meta,
+    usage: &Usage,
+    over_cap: bool,
+    now_ms: u64,
+    config: &Config,
+) -> Decision {
+    if over_cap {
+        // e.g. the daily cap got exceeded, the pace only spreads the quota of the window
+        Decision::Throttle(config.lower_limit_bytes)
+    } else if config.enforcement == Enforcement::PaceToReset {
+        Decision::Throttle(shaping::pace_rate(
+            usage.remaining_bytes,
+            usage.reset_at_ms.saturating_sub(now_ms),
+            config.lower_limit_bytes,
+            meta.throttled.then_some(meta.applied_rate),
+        ))
+    } else {
+        // e.g. the daily cap started over, a boost or refund or a connection with a higher cap
+        Decision::Release
+    }
+}
+
+/// What [`apply`] did about a decision.
+#[derive(Clone, Copy)]
+pub enum Outcome {
+    /// the limitation would apply if the trial period was over, `first` is set for the first
+    /// check over the cap
+    Trial {
+        rate: u64,
+        first: bool,
+    },
+    /// the limitation got applied, `previous` is the rate it replaced and `reshared` is set if it
+    /// got applied anew because the usage shares of the interfaces shifted
+    Limited {
+        rate: u64,
+        previous: Option<u64>,
+        reshared: bool,
+    },
+    /// the limitation already applies as decided
+    Kept,
+    /// the limitation couldn't be applied, the usage only gets observed
+    Failed,
+    Released,
+    /// nothing to do, e.g. while the enforcement is paused
+    Idle,
+}
+
+/// Applies `decision` once the trial period is over while the enforcement isn't paused.
+/// `observed_over_cap` keeps the trial period from reporting every check over the cap.
+pub fn apply(
+    meta: &mut Meta,
+    decision: Decision,
+    over_cap: bool,
+    now_ms: u64,
+    observed_over_cap: &mut bool,
+    config: &Config,
+    shaper: &mut impl Shaper,
+) -> Outcome {
+    let enforcing = config.enforcing_at(now_ms);
+    let paused = meta.paused(now_ms);
+    match decision {
+        Decision::Throttle(rate) if !enforcing => {
+            let first = !*observed_over_cap;
+            *observed_over_cap = true;
+            Outcome::Trial { rate, first }
+        }
+        Decision::Throttle(rate) if !paused => {
+            let previous = meta.throttled.then_some(meta.applied_rate);
+            let reshared = previous.is_some()
+                && config.throttle_split == ThrottleSplit::Proportional
+                && shaping::shares_shifted(
+                    &meta.applied_shares,
+                    &shaping::shares(config, &meta.interface_rates),
+                );
+            if previous.is_some_and(|previous| previous != rate) || reshared {
+                shaper.release(config);
+                meta.set_throttled(false);
+            }
+            if meta.throttled {
+                return Outcome::Kept;
+            }
+            if shaper.limit(rate, &meta.interface_rates, config).is_err() {
+                return Outcome::Failed;
+            }
+            meta.applied_rate = rate;
+            meta.applied_shares = shaping::shares(config, &meta.interface_rates);
+            meta.set_throttled(true);
+            Outcome::Limited {
+                rate,
+                previous,
+                reshared,
+            }
+        }
+        Decision::Release if enforcing && meta.throttled => {
+            shaper.release(config);
+            meta.set_throttled(false);
+            Outcome::Released
+        }
+        _ => {
+            *observed_over_cap = over_cap;
+            Outcome::Idle
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use num_bigint::BigUint;
+
+    use super::{apply, built_in, over_cap, Outcome, Shaper};
+    use crate::{fixtures, policy::Decision, shaping::Enforcement, Config, Meta, Usage};
+
+    /// Records the rates the limitation got applied with, `None` for its removal.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<Option<u64>>,
+        failing: bool,
+    }
+
+    impl Shaper for Recorder {
+        fn limit(
+            &mut self,
+            rate: u64,
+            _rates: &BTreeMap<String, f64>,
+            _config: &Config,
+        ) -> Result<(), String> {
+            if self.failing {
+                return Err("the kernel lacks sch_tbf".to_string());
+            }
+            self.calls.push(Some(rate));
+            Ok(())
+        }
+
+        fn release(&mut self, _config: &Config) {
+            self.calls.push(None);
+        }
+    }
+
+    const NOW_MS: u64 = 1_700_000_000_000;
+
+    fn check(meta: &mut Meta, curr_bytes: u64, config: &Config, shaper: &mut Recorder) -> Outcome {
+        let usage = Usage::compute(meta, &BigUint::from(curr_bytes), config);
+        let over_cap = over_cap(meta, &usage, NOW_MS, config);
+        let decision = built_in(meta, &usage, over_cap, NOW_MS, config);
+        apply(meta, decision, over_cap, NOW_MS, &mut false, config, shaper)
+    }
+
+    fn config() -> Config {
+        Config {
+            max_bytes: Some(10_000),
+            lower_limit_bytes: 100,
+            // the shares of the limit depend on the interfaces of the host otherwise
+            ..fixtures::config()
+        }
+    }
+
+    #[test]
+    fn releases_once_the_cap_got_raised() {
+        let config = config();
+        let mut meta = Meta::fresh("0".to_string(), &config);
+        let mut shaper = Recorder::default();
+        assert!(matches!(
+            check(&mut meta, 9000, &config, &mut shaper),
+            Outcome::Idle
+        ));
+        assert!(matches!(
+            check(&mut meta, 12_000, &config, &mut shaper),
+            Outcome::Limited {
+                rate: 100,
+                previous: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            check(&mut meta, 12_500, &config, &mut shaper),
+            Outcome::Kept
+        ));
+        let config = Config {
+            max_bytes: Some(20_000),
+            ..config
+        };
+        assert!(matches!(
+            check(&mut meta, 12_500, &config, &mut shaper),
+            Outcome::Released
+        ));
+        assert!(!meta.throttled);
+        assert_eq!(shaper.calls, [Some(100), None]);
+    }
+
+    #[test]
+    fn stays_unthrottled_if_the_limitation_fails() {
+        let config = config();
+        let mut meta = Meta::fresh("0".to_string(), &config);
+        let mut shaper = Recorder {
+            failing: true,
+            ..Recorder::default()
+        };
+        assert!(matches!(
+            check(&mut meta, 12_000, &config, &mut shaper),
+            Outcome::Failed
+        ));
+        assert!(!meta.throttled);
+        // the next check tries again
+        shaper.failing = false;
+        assert!(matches!(
+            check(&mut meta, 12_000, &config, &mut shaper),
+            Outcome::Limited { .. }
+        ));
+        assert!(meta.throttled);
+    }
+
+    #[test]
+    fn only_observes_during_the_trial_period() {
+        let config = Config {
+            enforcement_start_at_ms: Some(NOW_MS + 1),
+            ..config()
+        };
+        let mut meta = Meta::fresh("0".to_string(), &config);
+        let mut shaper = Recorder::default();
+        assert!(matches!(
+            check(&mut meta, 12_000, &config, &mut shaper),
+            Outcome::Trial {
+                rate: 100,
+                first: true
+            }
+        ));
+        assert!(!meta.throttled);
+        assert!(shaper.calls.is_empty());
+    }
+
+    #[test]
+    fn paces_down_to_the_throttle_rate_over_the_daily_cap() {
+        let config = Config {
+            enforcement: Enforcement::PaceToReset,
+            daily_max_bytes: Some(1000),
+            ..config()
+        };
+        let mut meta = Meta::fresh("0".to_string(), &config);
+        meta.reset_at_ms = NOW_MS + 1000;
+        let usage = Usage::compute(&meta, &BigUint::from(500u32), &config);
+        assert!(matches!(
+            built_in(&meta, &usage, false, NOW_MS, &config),
+            Decision::Throttle(9500)
+        ));
+        meta.daily_bytes = "2000".to_string();
+        let usage = Usage::compute(&meta, &BigUint::from(2000u32), &config);
+        let over_cap = over_cap(&mut meta, &usage, NOW_MS, &config);
+        assert!(over_cap);
+        assert!(matches!(
+            built_in(&meta, &usage, over_cap, NOW_MS, &config),
+            Decision::Throttle(100)
+        ));
+    }
+}
@@ -0,0 +1,293 @@
+//! Command line subcommands, everything not handled here is forwarded to the running daemon.
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    env,
+    fs::{self, DirBuilder},
+    os::unix::fs::DirBuilderExt,
+    path::Path,
+    process::{self, Command},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    config_path, control, crypto, current_time_millis, forecast, format_millis, history,
+    interfaces, log, shaping, signals, Config, Meta,
+};
+
+pub fn run(args: &[String]) {
+    match args[0].as_str() {
+        "test-throttle" => match args.get(1).map(|secs| secs.parse::<u64>()) {
+            Some(Ok(secs)) => test_throttle(secs),
+            _ => eprintln!("Usage: test-throttle <seconds>"),
+        },
+        "init" => init(),
+        "doctor" => doctor(),
+        "test-filter" => test_filter(),
+        "report" => report(),
+        "forecast" => forecast(),
+        "replay" => match args.get(1) {
+            Some(trace) => crate::replay::run(Path::new(trace), args.get(2).map(Path::new)),
+            None => eprintln!("Usage: replay <trace-file> [config-file]"),
+        },
+        "support-bundle" => support_bundle(),
+        #[cfg(feature = "snmp")]
+        "snmp-pass-persist" => crate::snmp::pass_persist(
+            args.get(1)
+                .map(String::as_str)
+                .unwrap_or(crate::snmp::DEFAULT_BASE_OID),
+        ),
+        "qdisc-status" => {
+            let config = Config::load();
+            let status = shaping::qdisc_status(&config);
+            println!("{}", serde_json::to_string_pretty(&status).unwrap());
+        }
+        "stream" => {
+            if let Err(err) = control::stream("stream") {
+                eprintln!("Couldn't reach the daemon: {err}");
+            }
+        }
+        _ => match control::request(&args.join(" ")) {
+            Ok(response) => print!("{response}"),
+            Err(err) => eprintln!("Couldn't reach the daemon: {err}"),
+        },
+    }
+}
+
+/// Applies the limitation for `secs` seconds without touching the quota state, so that an
+/// operator can check whether shaping is effective on this host.
+fn test_throttle(secs: u64) {
+    let config = Config::load().for_connection(None);
+    signals::install_interrupt();
+    log(&format!(
+        "Testing limitation of {} bytes per second for {secs} seconds...",
+        config.lower_limit_bytes
+    ));
+    if let Err(err) =
+        shaping::enable_lower_bandwidth(config.lower_limit_bytes, &config, &BTreeMap::new())
+    {
+        eprintln!("Shaping doesn't work on this host: {err}");
+        return;
+    }
+    for _ in 0..secs {
+        if signals::interrupted() {
+            log("Interrupted, removing the test limitation early...");
+            break;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    shaping::disable_lowered_bandwidth(&config);
+}
+
+/// Creates the default config without starting the daemon.
+fn init() {
+    let path = config_path("config.json");
+    if path.exists() {
+        eprintln!("There already is a config at {}", path.display());
+        return;
+    }
+    Config::defaults().write(&path);
+    println!("Created a default config at {}", path.display());
+}
+
+/// Checks the environment the daemon runs in and prints what it found.
+fn doctor() {
+    print!("{}", doctor_report());
+}
+
+fn doctor_report() -> String {
+    let mut report = String::new();
+    let mut check = |name: &str, result: Result<String, String>| match result {
+        Ok(info) => report.push_str(&format!("[ok]   {name}: {info}\n")),
+        Err(err) => report.push_str(&format!("[fail] {name}: {err}\n")),
+    };
+    check(
+        "interfaces",
+        fs::read_dir("/sys/class/net")
+            .map(|dir| {
+                dir.flatten()
+                    .map(|interface| interface.file_name().to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .map_err(|err| err.to_string()),
+    );
+    check(
+        "tc",
+        Command::new("tc")
+            .arg("-V")
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .map_err(|err| err.to_string()),
+    );
+    check(
+        "daemon",
+        control::request("timers")
+            .map(|timers| format!("reachable\n{}", timers.trim_end()))
+            .map_err(|err| err.to_string()),
+    );
+    report
+}
+
+/// Shows which interfaces the configured include and exclude patterns resolve to.
+fn test_filter() {
+    let selection = interfaces::select(&Config::load());
+    println!("included: {}", selection.included.join(", "));
+    println!("excluded: {}", selection.excluded.join(", "));
+    println!("counted:  {}", selection.counted.join(", "));
+    println!("inbound:  {}", selection.inbound.join(", "));
+    if selection.counted.is_empty() {
+        println!(
+            "Warning: no interface is counted, check include_interfaces and exclude_interfaces"
+        );
+    }
+}
+
+/// config values containing one of these get replaced, hook commands may contain tokens too
+const SECRET_MARKERS: [&str; 7] = [
+    "password", "token", "secret", "key", "webhook", "url", "commands",
+];
+
+/// Writes everything needed to troubleshoot this host into a tarball for attaching to bug reports.
+fn support_bundle() {
+    let config = Config::load();
+    let dir = env::temp_dir().join(format!(
+        "traffic_tracker_bundle_{}_{}",
+        process::id(),
+        current_time_millis()
+    ));
+    // the temp dir is shared with other users, so the directory has to be a fresh one only we can
+    // access, otherwise someone could redirect the writes through symlinks planted in it
+    if let Err(err) = DirBuilder::new().mode(0o700).create(&dir) {
+        eprintln!("Couldn't create {}: {err}", dir.display());
+        return;
+    }
+    let mut redacted = serde_json::to_value(&config).unwrap();
+    if let Some(fields) = redacted.as_object_mut() {
+        for (name, value) in fields {
+            if SECRET_MARKERS.iter().any(|marker| name.contains(marker)) && !value.is_null() {
+                *value = serde_json::Value::String("<redacted>".to_string());
+            }
+        }
+    }
+    let meta = crypto::init(&config)
+        .and_then(|_| fs::read(Meta::path()).map_err(|err| err.to_string()))
+        .and_then(crypto::open)
+        .map(|raw| String::from_utf8_lossy(&raw).into_owned())
+        .unwrap_or_else(|err| format!("Couldn't read the state: {err}"));
+    let logs = output_of(Command::new("journalctl").args([
+        "--no-pager",
+        "-n",
+        "1000",
+        "_COMM=TrafficTracker",
+    ]));
+    let qdiscs = interfaces::all()
+        .iter()
+        .map(|interface| {
+            format!(
+                "{interface}:\n{}",
+                output_of(Command::new("tc").args(["qdisc", "show", "dev", interface]))
+            )
+        })
+        .collect::<String>();
+    let files = [
+        (
+            "config.json",
+            serde_json::to_string_pretty(&redacted).unwrap(),
+        ),
+        ("meta.json", meta),
+        ("log.txt", logs),
+        ("doctor.txt", doctor_report()),
+        ("qdiscs.txt", qdiscs),
+    ];
+    for (name, content) in files {
+        fs::write(dir.join(name), content).unwrap();
+    }
+    let bundle = format!("traffic_tracker_support_{}.tar.gz", current_time_millis());
+    let packed = Command::new("tar")
+        .arg("-czf")
+        .arg(&bundle)
+        .arg("-C")
+        .arg(&dir)
+        .arg(".")
+        .status();
+    let _ = fs::remove_dir_all(&dir);
+    match packed {
+        Ok(status) if status.success() => println!("Wrote {bundle}"),
+        Ok(status) => eprintln!("Couldn't write {bundle}: tar exited with {status}"),
+        Err(err) => eprintln!("Couldn't run tar: {err}"),
+    }
+}
+
+/// Stdout of `command`, or why it couldn't be run.
+fn output_of(command: &mut Command) -> String {
+    match command.output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+        Err(err) => format!("Couldn't run {:?}: {err}\n", command.get_program()),
+    }
+}
+
+/// Prints when the cap is expected to be hit according to the history and the current usage.
+fn forecast() {
+    let status = match control::request("status") {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("Couldn't reach the daemon: {err}");
+            return;
+        }
+    };
+    let usage = serde_json::from_str::<serde_json::Value>(&status).unwrap_or_default();
+    let (Some(remaining_bytes), Some(reset_at_ms)) = (
+        usage["remaining_bytes"].as_u64(),
+        usage["reset_at_ms"].as_u64(),
+    ) else {
+        eprintln!("Unexpected status from the daemon: {status}");
+        return;
+    };
+    let forecast = forecast::project(
+        &history::load(),
+        remaining_bytes,
+        usage["smoothed_rate_bytes_per_sec"]
+            .as_f64()
+            .unwrap_or_default(),
+        current_time_millis() as u64,
+        reset_at_ms,
+    );
+    let basis = if forecast.from_history {
+        "usage pattern of past windows"
+    } else {
+        "current rate, there's no usable history yet"
+    };
+    match forecast.cap_hit_at_ms {
+        Some(at_ms) => println!("The cap will be hit at {}", format_millis(at_ms)),
+        None => println!(
+            "The cap won't be hit before the window resets at {}",
+            format_millis(reset_at_ms)
+        ),
+    }
+    println!("confidence: {} (based on the {basis})", forecast.confidence);
+}
+
+/// Prints the usage of every past window.
+fn report() {
+    let windows = history::load();
+    if windows.is_empty() {
+        println!("No window has been completed yet");
+    }
+    for window in windows {
+        println!(
+            "window ended {}: {} bytes used, {} bytes off-peak, throttled for {}s",
+            format_millis(window.ended_at_ms),
+            window.used_bytes,
+            window.off_peak_bytes,
+            window.throttled_ms / 1000
+        );
+        let mut interfaces = window.interface_bytes.into_iter().collect::<Vec<_>>();
+        interfaces.sort_by_key(|(_, bytes)| Reverse(*bytes));
+        for (interface, bytes) in interfaces {
+            println!("  {interface}: {bytes} bytes");
+        }
+    }
+}
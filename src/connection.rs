@@ -0,0 +1,81 @@
+//! Detection of the type of connection the default route goes through.
+
+use std::{fs, path::Path};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Which interfaces count as the egress when there are multiple default routes (e.g. ECMP).
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MultiDefaultRoute {
+    /// all interfaces carrying a default route
+    #[default]
+    All,
+    /// only the interface(s) of the default route with the lowest metric
+    LowestMetric,
+}
+
+const RTF_UP: u32 = 0x1;
+
+/// Returns every interface carrying an active default route together with the route's metric.
+fn default_routes() -> Vec<(String, u32)> {
+    let routes = fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let mut default_routes = routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let columns = line.split_whitespace().collect::<Vec<_>>();
+            let (interface, destination, flags, metric) = (
+                columns.first()?,
+                columns.get(1)?,
+                columns.get(3)?,
+                columns.get(6)?,
+            );
+            let flags = u32::from_str_radix(flags, 16).ok()?;
+            (*destination == "00000000" && flags & RTF_UP != 0)
+                .then(|| Some((interface.to_string(), metric.parse().ok()?)))
+                .flatten()
+        })
+        .collect::<Vec<_>>();
+    default_routes.sort_by_key(|(_, metric)| *metric);
+    default_routes
+}
+
+/// Returns the egress interfaces according to `policy`, empty if there is no default route.
+pub fn default_route_interfaces(policy: MultiDefaultRoute) -> Vec<String> {
+    let routes = default_routes();
+    let lowest = routes.first().map(|(_, metric)| *metric);
+    let mut interfaces = routes
+        .into_iter()
+        .filter(|(_, metric)| policy == MultiDefaultRoute::All || Some(*metric) == lowest)
+        .map(|(interface, _)| interface)
+        .collect::<Vec<_>>();
+    interfaces.dedup();
+    interfaces
+}
+
+/// Returns the interface carrying the default route with the lowest metric, if any.
+pub fn default_route_interface() -> Option<String> {
+    default_routes()
+        .into_iter()
+        .next()
+        .map(|(interface, _)| interface)
+}
+
+/// Classifies `interface` as `"wifi"`, `"lte"` or `"ethernet"`.
+pub fn connection_type(interface: &str) -> &'static str {
+    let dir = Path::new("/sys/class/net").join(interface);
+    if dir.join("wireless").exists() || dir.join("phy80211").exists() {
+        return "wifi";
+    }
+    let uevent = fs::read_to_string(dir.join("uevent")).unwrap_or_default();
+    if uevent.lines().any(|line| line == "DEVTYPE=wwan") {
+        return "lte";
+    }
+    "ethernet"
+}
+
+/// Returns the type of the currently active egress connection.
+pub fn detect() -> Option<&'static str> {
+    default_route_interface().map(|interface| connection_type(&interface))
+}
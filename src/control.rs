@@ -0,0 +1,251 @@
+//! Control socket of the daemon.
+//!
+//! Clients send a single command line and receive the response until the daemon closes the connection.
+//! The `stream` command keeps the connection open and sends every [`events::Event`] as a JSON line.
+
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::{
+    calibration::{self, CalibrationPoint},
+    config_path, current_time_millis, duration, ephemeral, events, format_millis, log, panic_reset,
+    reevaluate, shaping, wan, Boost, Config, Meta, Pause, Usage,
+};
+
+/// overrides the socket clients connect to, e.g. to reach an ephemeral daemon
+pub const SOCKET_ENV: &str = "TRAFFIC_TRACKER_SOCKET";
+
+pub fn socket_path() -> PathBuf {
+    env::var_os(SOCKET_ENV).map_or_else(|| config_path("control.sock"), PathBuf::from)
+}
+
+/// An ephemeral daemon gets a socket of its own so that it doesn't take over the one of a daemon
+/// running alongside.
+fn ephemeral_socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("traffic_tracker_{}.sock", process::id()))
+}
+
+pub fn spawn(meta: Arc<Mutex<Meta>>, config: Arc<Config>) {
+    let path = if ephemeral() {
+        let path = ephemeral_socket_path();
+        log(&format!(
+            "Control socket is {}, set {SOCKET_ENV} to it to reach this instance",
+            path.display()
+        ));
+        path
+    } else {
+        let path = socket_path();
+        let _ = fs::create_dir_all(path.parent().unwrap());
+        path
+    };
+    // a previous instance may have left its socket behind
+    let _ = fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log(&format!(
+                "Couldn't bind control socket {}: {err}",
+                path.display()
+            ));
+            return;
+        }
+    };
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let meta = meta.clone();
+            let config = config.clone();
+            // connections may stay open for a long time when streaming, so handle each separately
+            thread::spawn(move || {
+                if let Err(err) = handle(stream, &meta, &config) {
+                    log(&format!("Error handling control connection: {err}"));
+                }
+            });
+        }
+    });
+}
+
+fn handle(stream: UnixStream, meta: &Mutex<Meta>, config: &Config) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match line.trim() {
+        "panic" => {
+            panic_reset(&mut meta.lock().unwrap(), config);
+            format!(
+                "Restrictions removed, enforcement paused for {}ms",
+                config.panic_cooldown_ms
+            )
+        }
+        "timers" => {
+            let meta = meta.lock().unwrap();
+            meta.timers.report(meta.reset_at_ms)
+        }
+        "status" => {
+            let mut meta = meta.lock().unwrap();
+            let curr_bytes = meta.sample(config);
+            let config = config.for_connection(meta.connection);
+            let mut usage = Usage::compute(&meta, &curr_bytes, &config);
+            drop(meta);
+            usage.qdiscs = Some(shaping::qdisc_status(&config));
+            serde_json::to_string_pretty(&usage).unwrap()
+        }
+        "rediscover" => match wan::rediscover() {
+            Some(interface) => format!("Using {interface} as the WAN interface"),
+            None => "Couldn't discover a WAN interface, there is no default route".to_string(),
+        },
+        "stream" => {
+            for line in events::subscribe() {
+                let sent = (&stream)
+                    .write_all(line.as_bytes())
+                    .and_then(|_| (&stream).write_all(b"\n"));
+                // the subscriber went away
+                if sent.is_err() {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        other => match other.split_whitespace().collect::<Vec<_>>()[..] {
+            ["boost", bytes, duration] => {
+                match (bytes.parse::<u64>(), duration::parse_ms(duration)) {
+                    (Ok(bytes), Ok(duration_ms)) => {
+                        let mut meta = meta.lock().unwrap();
+                        let expires_at_ms = current_time_millis() as u64 + duration_ms;
+                        meta.boost = Some(Boost {
+                            bytes,
+                            expires_at_ms,
+                        });
+                        meta.store();
+                        reevaluate();
+                        format!(
+                            "Cap raised by {bytes} bytes until {}",
+                            format_millis(expires_at_ms)
+                        )
+                    }
+                    (Err(err), _) => format!("Invalid byte count `{bytes}`: {err}"),
+                    (_, Err(err)) => err,
+                }
+            }
+            ["pause", duration, ref rest @ ..] if matches!(rest, [] | ["enforcement"]) => {
+                match duration::parse_ms(duration) {
+                    Ok(duration_ms) => {
+                        let mut meta = meta.lock().unwrap();
+                        if meta.throttled {
+                            shaping::disable_lowered_bandwidth(config);
+                            meta.set_throttled(false);
+                        }
+                        let now = current_time_millis() as u64;
+                        // `pause <duration> enforcement` keeps counting
+                        let pause = Pause {
+                            started_at_ms: now,
+                            until_ms: Some(now + duration_ms),
+                            gates_baseline: rest.is_empty(),
+                        };
+                        let response = pause.describe();
+                        meta.pause = Some(pause);
+                        meta.store();
+                        response
+                    }
+                    Err(err) => err,
+                }
+            }
+            ["calibrate", isp_bytes] => match isp_bytes.parse::<u64>() {
+                Ok(isp_bytes) => {
+                    let mut meta = meta.lock().unwrap();
+                    let curr_bytes = meta.sample(config);
+                    let measured_bytes = meta.raw_used_bytes(&curr_bytes);
+                    // the usage which couldn't be counted gets added on top of the corrected count
+                    let isp_bytes = isp_bytes.saturating_sub(meta.usage_offset_bytes);
+                    if measured_bytes == 0 {
+                        "Nothing was counted in this window yet, calibrate later".to_string()
+                    } else if isp_bytes == 0 {
+                        format!(
+                            "The ISP figure has to exceed the {} bytes set as used before counting",
+                            meta.usage_offset_bytes
+                        )
+                    } else if let Err(err) = calibration::append(&CalibrationPoint {
+                        at_ms: current_time_millis() as u64,
+                        isp_bytes,
+                        measured_bytes,
+                    }) {
+                        format!("Couldn't store the calibration point: {err}")
+                    } else {
+                        meta.calibrated_correction = calibration::factor();
+                        reevaluate();
+                        format!(
+                            "Counted {measured_bytes} bytes, correction factor is now {:.4}",
+                            meta.correction(config)
+                        )
+                    }
+                }
+                Err(err) => format!("Invalid byte count `{isp_bytes}`: {err}"),
+            },
+            ["refund", bytes] => match bytes.parse::<u64>() {
+                Ok(bytes) => {
+                    let mut meta = meta.lock().unwrap();
+                    let curr_bytes = meta.sample(config);
+                    let refunded = meta.refund(bytes, &curr_bytes);
+                    meta.store();
+                    reevaluate();
+                    log(&format!("Refunded {refunded} bytes of the current window"));
+                    format!("Refunded {refunded} bytes")
+                }
+                Err(err) => format!("Invalid byte count `{bytes}`: {err}"),
+            },
+            ["set-usage", bytes] => match bytes.parse::<u64>() {
+                Ok(bytes) => {
+                    let mut meta = meta.lock().unwrap();
+                    let curr_bytes = meta.sample(config);
+                    // the given figure replaces everything counted in this window so far
+                    meta.starting_bytes = curr_bytes.to_string();
+                    meta.off_peak_bytes = "0".to_string();
+                    meta.usage_offset_bytes = bytes;
+                    meta.store();
+                    reevaluate();
+                    log(&format!("Usage of the current window set to {bytes} bytes"));
+                    format!("Usage set to {bytes} bytes")
+                }
+                Err(err) => format!("Invalid byte count `{bytes}`: {err}"),
+            },
+            ["resume"] => {
+                let mut meta = meta.lock().unwrap();
+                meta.pause = None;
+                meta.store();
+                reevaluate();
+                "Resumed".to_string()
+            }
+            _ => format!("Unknown command: {other}"),
+        },
+    };
+    (&stream).write_all(response.as_bytes())?;
+    (&stream).write_all(b"\n")
+}
+
+/// Sends `command` to the running daemon and returns its response.
+pub fn request(command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket_path())?;
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// Sends `command` to the running daemon and prints every line of the response as soon as it arrives.
+pub fn stream(command: &str) -> io::Result<()> {
+    let mut stream = UnixStream::connect(socket_path())?;
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+    for line in BufReader::new(stream).lines() {
+        println!("{}", line?);
+    }
+    Ok(())
+}
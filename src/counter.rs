@@ -0,0 +1,102 @@
+//! Weighted byte counter the usage gets computed from.
+//!
+//! It advances by the weighted delta of every interface counter since the previous sample, so
+//! changing a weight doesn't shift the traffic counted before and a reset interface counter
+//! doesn't lose it. Interfaces which start being counted mid-window (e.g. a rediscovered WAN
+//! interface) only count from then on instead of with their whole counter.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use num_bigint::BigUint;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Counter {
+    /// weighted bytes counted so far, empty for states written before the counter existed
+    pub bytes: String,
+    /// interface counters of the previous sample
+    pub last: BTreeMap<String, u64>,
+}
+
+impl Counter {
+    pub fn starting_at(bytes: &str) -> Self {
+        Counter {
+            bytes: bytes.to_string(),
+            last: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the delta of every counter since the previous sample multiplied by its `weight` and
+    /// returns the new value. A counter seen for the first time only counts from now on.
+    pub fn advance(&mut self, counters: &[(String, u64)], weight: impl Fn(&str) -> f64) -> BigUint {
+        let mut bytes = BigUint::from_str(&self.bytes).unwrap_or_default();
+        for (counter, curr) in counters {
+            let delta = delta(self.last.get(counter).copied(), *curr);
+            let weight = weight(counter);
+            if weight == 1.0 {
+                bytes += delta;
+            } else {
+                bytes += (delta as f64 * weight).round() as u64;
+            }
+        }
+        self.bytes = bytes.to_string();
+        self.last = counters.iter().cloned().collect();
+        bytes
+    }
+}
+
+/// Bytes an interface counter advanced by since it read `last`. Everything a reset counter (e.g.
+/// after a reboot) holds is new, a counter seen for the first time only counts from now on.
+pub fn delta(last: Option<u64>, curr: u64) -> u64 {
+    match last {
+        Some(last) if curr >= last => curr - last,
+        Some(_) => curr,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use super::Counter;
+    use crate::fixtures::counters;
+
+    #[test]
+    fn weights_the_deltas_per_interface() {
+        let weight = |interface: &str| if interface == "wwan0" { 0.5 } else { 1.0 };
+        let mut counter = Counter::starting_at("0");
+        let start = counters(&[("eth0", 5000), ("wwan0", 7000)]);
+        // the counters hold traffic from before
+        assert_eq!(counter.advance(&start, weight), BigUint::ZERO);
+        let next = counters(&[("eth0", 6000), ("wwan0", 9000)]);
+        assert_eq!(
+            counter.advance(&next, weight),
+            BigUint::from(1000u32 + 1000)
+        );
+    }
+
+    #[test]
+    fn changing_a_weight_only_affects_later_traffic() {
+        let mut counter = Counter::starting_at("0");
+        counter.advance(&counters(&[("eth0", 1000)]), |_| 1.0);
+        counter.advance(&counters(&[("eth0", 2000)]), |_| 1.0);
+        let bytes = counter.advance(&counters(&[("eth0", 2000)]), |_| 0.25);
+        assert_eq!(bytes, BigUint::from(1000u32));
+        let bytes = counter.advance(&counters(&[("eth0", 3000)]), |_| 0.25);
+        assert_eq!(bytes, BigUint::from(1250u32));
+    }
+
+    #[test]
+    fn survives_resets_and_changed_interfaces() {
+        let mut counter = Counter::starting_at("100");
+        counter.advance(&counters(&[("eth0", 1000)]), |_| 1.0);
+        counter.advance(&counters(&[("eth0", 1500)]), |_| 1.0);
+        // a reboot resets the counter
+        counter.advance(&counters(&[("eth0", 200)]), |_| 1.0);
+        // another interface gets counted instead
+        counter.advance(&counters(&[("wlan0", 90_000)]), |_| 1.0);
+        let bytes = counter.advance(&counters(&[("wlan0", 90_300)]), |_| 1.0);
+        assert_eq!(bytes, BigUint::from(100u32 + 500 + 200 + 300));
+    }
+}
@@ -0,0 +1,86 @@
+//! Optional encryption of the state file at rest with AES-256-GCM.
+//!
+//! The key is 32 bytes given as 64 hex characters, either via the `TRAFFIC_TRACKER_STATE_KEY`
+//! environment variable or in the file configured as `state_key_file`.
+//! Encrypted files consist of [`MAGIC`], the nonce and the ciphertext.
+
+use std::{env, fs, sync::OnceLock};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+
+use crate::Config;
+
+const MAGIC: &[u8] = b"TTENC1";
+const NONCE_LEN: usize = 12;
+pub const KEY_ENV: &str = "TRAFFIC_TRACKER_STATE_KEY";
+
+static CIPHER: OnceLock<Option<Aes256Gcm>> = OnceLock::new();
+
+/// Loads the key if `encrypt_state` is enabled, has to be called before the state is accessed.
+pub fn init(config: &Config) -> Result<(), String> {
+    let cipher = if config.encrypt_state {
+        let raw = match (env::var(KEY_ENV), &config.state_key_file) {
+            (Ok(key), _) => key,
+            (Err(_), Some(file)) => fs::read_to_string(file)
+                .map_err(|err| format!("Couldn't read state key file {}: {err}", file.display()))?,
+            (Err(_), None) => {
+                return Err(format!(
+                    "encrypt_state is enabled but neither {KEY_ENV} nor state_key_file is set"
+                ))
+            }
+        };
+        let key = parse_hex(raw.trim())
+            .filter(|key| key.len() == 32)
+            .ok_or("The state key has to consist of exactly 64 hex characters")?;
+        Some(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    } else {
+        None
+    };
+    let _ = CIPHER.set(cipher);
+    Ok(())
+}
+
+fn cipher() -> Option<&'static Aes256Gcm> {
+    CIPHER.get().and_then(Option::as_ref)
+}
+
+/// Encrypts `plain` if encryption is enabled, otherwise it's returned as is.
+pub fn seal(plain: Vec<u8>) -> Vec<u8> {
+    let Some(cipher) = cipher() else {
+        return plain;
+    };
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut sealed = MAGIC.to_vec();
+    sealed.extend_from_slice(&nonce);
+    sealed.extend(cipher.encrypt(&nonce, plain.as_slice()).unwrap());
+    sealed
+}
+
+/// Decrypts `data` if it's encrypted. Plaintext is accepted even with encryption enabled so that
+/// existing state can be migrated, it gets encrypted on the next store.
+pub fn open(data: Vec<u8>) -> Result<Vec<u8>, String> {
+    let Some(sealed) = data.strip_prefix(MAGIC) else {
+        return Ok(data);
+    };
+    let cipher = cipher().ok_or("The state is encrypted but encrypt_state is disabled")?;
+    if sealed.len() < NONCE_LEN {
+        return Err("The encrypted state is truncated".to_string());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Couldn't decrypt the state, the key is probably wrong".to_string())
+}
+
+fn parse_hex(raw: &str) -> Option<Vec<u8>> {
+    if !raw.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..raw.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(raw.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,59 @@
+//! Deserialization of intervals which may either be given as raw milliseconds or as
+//! human-readable durations like `"10s"`, `"1m"` or `"7d"`.
+
+use serde::{de::Error, Deserialize as _, Deserializer};
+use serde_derive::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Millis(u64),
+    Human(String),
+}
+
+pub fn deserialize_ms<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match RawDuration::deserialize(deserializer)? {
+        RawDuration::Millis(ms) => Ok(ms),
+        RawDuration::Human(raw) => parse_ms(&raw).map_err(D::Error::custom),
+    }
+}
+
+pub fn parse_ms(raw: &str) -> Result<u64, String> {
+    humantime::parse_duration(raw.trim())
+        .map(|duration| duration.as_millis() as u64)
+        .map_err(|err| format!("invalid duration `{raw}`: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_derive::Deserialize;
+
+    use super::{deserialize_ms, parse_ms};
+
+    #[derive(Deserialize)]
+    struct Interval {
+        #[serde(deserialize_with = "deserialize_ms")]
+        ms: u64,
+    }
+
+    fn interval(raw: &str) -> Result<u64, serde_json::Error> {
+        serde_json::from_str::<Interval>(raw).map(|interval| interval.ms)
+    }
+
+    #[test]
+    fn parses_human_durations() {
+        assert_eq!(parse_ms("7d"), Ok(604_800_000));
+        assert_eq!(parse_ms("10s"), Ok(10_000));
+        assert_eq!(parse_ms(" 1m "), Ok(60_000));
+        assert_eq!(parse_ms("1h 30m"), Ok(5_400_000));
+        assert!(parse_ms("soon").is_err());
+    }
+
+    #[test]
+    fn deserializes_strings_and_integers() {
+        assert_eq!(interval(r#"{"ms": "7d"}"#).unwrap(), 604_800_000);
+        assert_eq!(interval(r#"{"ms": 5000}"#).unwrap(), 5000);
+        assert!(interval(r#"{"ms": "soon"}"#).is_err());
+        assert!(interval(r#"{"ms": -1}"#).is_err());
+    }
+}
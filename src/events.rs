@@ -0,0 +1,84 @@
+//! Structured events emitted by the daemon, streamed as JSON lines to `stream` subscribers.
+
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Mutex,
+};
+
+use serde_derive::Serialize;
+
+use crate::{current_time_millis, instance_label, Usage};
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// a check of the main loop completed
+    Tick {
+        usage: &'a Usage,
+    },
+    /// the limitation got applied or removed
+    Throttle {
+        throttled: bool,
+        rate: u64,
+    },
+    /// still throttled while waiting for the window to reset
+    Throttled {
+        reset_at_ms: u64,
+        remaining_ms: u64,
+    },
+    /// a new window started
+    Reset {
+        reset_at_ms: u64,
+    },
+    PanicReset {
+        paused_until_ms: u64,
+    },
+    /// the send rate suddenly exceeded the trailing average, see [`crate::anomaly`]
+    Anomaly {
+        rate: f64,
+        baseline: f64,
+        top_interface: Option<String>,
+    },
+    /// an alert tier got reached, see [`crate::alerts`]
+    Alert {
+        tier: &'a str,
+        used_bytes: u64,
+        max_bytes: u64,
+    },
+    /// the rate measured after applying the limitation is well above the limit
+    ThrottleIneffective {
+        target_rate: u64,
+        measured_rate: f64,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    at_ms: u64,
+    instance: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+static SUBSCRIBERS: Mutex<Vec<Sender<String>>> = Mutex::new(Vec::new());
+
+pub fn publish(event: Event) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    if subscribers.is_empty() {
+        return;
+    }
+    let line = serde_json::to_string(&Envelope {
+        at_ms: current_time_millis() as u64,
+        instance: instance_label(),
+        event: &event,
+    })
+    .unwrap();
+    subscribers.retain(|subscriber| subscriber.send(line.clone()).is_ok());
+}
+
+/// Returns a receiver getting every event published from now on as a JSON line.
+pub fn subscribe() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
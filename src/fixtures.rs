@@ -0,0 +1,21 @@
+//! Helpers shared by the tests of several modules.
+
+use crate::Config;
+
+pub fn counters(counters: &[(&str, u64)]) -> Vec<(String, u64)> {
+    counters
+        .iter()
+        .map(|(interface, bytes)| (interface.to_string(), *bytes))
+        .collect()
+}
+
+/// Defaults which select the same interfaces on every host, the WAN interface gets cached for the
+/// whole process otherwise.
+pub fn config() -> Config {
+    Config {
+        track_default_route_only: false,
+        wan_interface: None,
+        auto_discover_wan: false,
+        ..Config::defaults()
+    }
+}
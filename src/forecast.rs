@@ -0,0 +1,112 @@
+//! Projection of when the cap will be hit, based on the usage per hour of the day in past
+//! windows so that daily peaks like evening streaming are taken into account.
+
+use chrono::{DateTime, Local, Timelike};
+
+use crate::history::WindowRecord;
+
+const HOUR_MS: u64 = 1000 * 60 * 60;
+const DAY_MS: f64 = 1000.0 * 60.0 * 60.0 * 24.0;
+
+pub struct Forecast {
+    /// `None` if the cap won't be hit before the window resets
+    pub cap_hit_at_ms: Option<u64>,
+    pub confidence: &'static str,
+    /// whether the projection is based on the history or just the current rate
+    pub from_history: bool,
+}
+
+/// Bytes expected to be sent during every hour of a day. Windows are weighted by their age, the
+/// most recent one counting the most.
+fn daily_profile(windows: &[WindowRecord]) -> Option<[f64; 24]> {
+    let usable = windows
+        .iter()
+        .filter(|window| window.hourly_bytes.len() == 24 && window.window_ms != 0)
+        .collect::<Vec<_>>();
+    if usable.is_empty() {
+        return None;
+    }
+    let mut profile = [0.0; 24];
+    let mut total_weight = 0.0;
+    for (age, window) in usable.iter().rev().enumerate() {
+        let weight = 1.0 / (age + 1) as f64;
+        let days = window.window_ms as f64 / DAY_MS;
+        for (hour, bytes) in window.hourly_bytes.iter().enumerate() {
+            profile[hour] += weight * *bytes as f64 / days;
+        }
+        total_weight += weight;
+    }
+    Some(profile.map(|bytes| bytes / total_weight))
+}
+
+/// How much the usage of full windows varied, which decreases the confidence.
+fn confidence(windows: &[WindowRecord]) -> &'static str {
+    let totals = windows
+        .iter()
+        .filter(|window| window.window_ms != 0)
+        .map(|window| window.used_bytes as f64 / (window.window_ms as f64 / DAY_MS))
+        .collect::<Vec<_>>();
+    if totals.len() < 2 {
+        return "low";
+    }
+    let mean = totals.iter().sum::<f64>() / totals.len() as f64;
+    let variance = totals
+        .iter()
+        .map(|total| (total - mean).powi(2))
+        .sum::<f64>()
+        / totals.len() as f64;
+    let spread = if mean > 0.0 {
+        variance.sqrt() / mean
+    } else {
+        1.0
+    };
+    match (totals.len(), spread) {
+        (4.., spread) if spread < 0.25 => "high",
+        (_, spread) if spread < 0.5 => "medium",
+        _ => "low",
+    }
+}
+
+/// Projects when `remaining_bytes` will be used up, walking hour by hour through the daily
+/// profile of `windows`, or linearly by `rate` if there's no usable history.
+pub fn project(
+    windows: &[WindowRecord],
+    remaining_bytes: u64,
+    rate: f64,
+    now_ms: u64,
+    reset_at_ms: u64,
+) -> Forecast {
+    let Some(profile) = daily_profile(windows) else {
+        let cap_hit_at_ms = (rate >= 1.0)
+            .then(|| now_ms + (remaining_bytes as f64 / rate * 1000.0) as u64)
+            .filter(|at_ms| *at_ms < reset_at_ms);
+        return Forecast {
+            cap_hit_at_ms,
+            confidence: "low",
+            from_history: false,
+        };
+    };
+    let mut remaining = remaining_bytes as f64;
+    let mut at_ms = now_ms;
+    let mut cap_hit_at_ms = None;
+    while at_ms < reset_at_ms {
+        let Some(time) = DateTime::from_timestamp_millis(at_ms as i64) else {
+            break;
+        };
+        let time = time.with_timezone(&Local);
+        // the first step only covers what's left of the current hour
+        let step_ms = HOUR_MS - (time.minute() as u64 * 60 + time.second() as u64) * 1000;
+        let expected = profile[time.hour() as usize] * step_ms as f64 / HOUR_MS as f64;
+        if expected >= remaining {
+            cap_hit_at_ms = Some(at_ms + (remaining / expected * step_ms as f64) as u64);
+            break;
+        }
+        remaining -= expected;
+        at_ms += step_ms;
+    }
+    Forecast {
+        cap_hit_at_ms: cap_hit_at_ms.filter(|at_ms| *at_ms < reset_at_ms),
+        confidence: confidence(windows),
+        from_history: true,
+    }
+}
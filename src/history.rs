@@ -0,0 +1,56 @@
+//! Per-window history, stored as one JSON record per line.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{config_path, ephemeral, log};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WindowRecord {
+    pub ended_at_ms: u64,
+    pub used_bytes: u64,
+    pub off_peak_bytes: u64,
+    pub throttled_ms: u64,
+    /// length of the window, 0 for records written before this got recorded
+    #[serde(default)]
+    pub window_ms: u64,
+    /// bytes sent during every hour of the day (local time), see [`crate::forecast`]
+    #[serde(default)]
+    pub hourly_bytes: Vec<u64>,
+    /// bytes per counter, received bytes are keyed by `<interface>:rx`
+    #[serde(default)]
+    pub interface_bytes: BTreeMap<String, u64>,
+}
+
+fn path() -> PathBuf {
+    config_path("history.jsonl")
+}
+
+pub fn append(record: &WindowRecord) {
+    if ephemeral() {
+        return;
+    }
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path())
+        .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(record).unwrap()));
+    if let Err(err) = result {
+        log(&format!("Couldn't append to history: {err}"));
+    }
+}
+
+/// Loads all recorded windows, oldest first.
+pub fn load() -> Vec<WindowRecord> {
+    fs::read_to_string(path())
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
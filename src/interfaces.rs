@@ -0,0 +1,234 @@
+//! Selection of the interfaces whose traffic gets counted.
+
+use std::{
+    fs,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{connection, log, wan, Config};
+
+/// whether we already warned about missing default routes since they went away
+static NO_DEFAULT_ROUTE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Names of all interfaces currently present.
+pub fn all() -> Vec<String> {
+    let mut interfaces = fs::read_dir("/sys/class/net")
+        .unwrap()
+        .flatten()
+        .map(|interface| interface.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    interfaces.sort();
+    interfaces
+}
+
+pub struct Selection {
+    /// interfaces matching `include_interfaces`, all of them if no include pattern is configured
+    pub included: Vec<String>,
+    /// included interfaces which are excluded again by `exclude_interfaces`, for being virtual,
+    /// for not matching `outbound_interfaces`, for not carrying the default route or for not
+    /// being the WAN interface
+    pub excluded: Vec<String>,
+    /// interfaces whose sent bytes are counted
+    pub counted: Vec<String>,
+    /// interfaces whose received bytes are counted
+    pub inbound: Vec<String>,
+}
+
+pub fn select(config: &Config) -> Selection {
+    select_among(config, all(), is_virtual)
+}
+
+/// Selects among the interfaces `present`, `virtual_` tells whether one is virtual.
+fn select_among(
+    config: &Config,
+    present: Vec<String>,
+    virtual_: impl Fn(&str) -> bool,
+) -> Selection {
+    let matches_any = |patterns: &[String], interface: &str| {
+        patterns
+            .iter()
+            .any(|pattern| glob_match(pattern, interface))
+    };
+    let included = present
+        .iter()
+        .filter(|interface| {
+            config.include_interfaces.is_empty()
+                || matches_any(&config.include_interfaces, interface)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let (mut excluded, mut counted): (Vec<_>, Vec<_>) =
+        included.iter().cloned().partition(|interface| {
+            matches_any(&config.exclude_interfaces, interface)
+                || (!config.count_virtual_interfaces && virtual_(interface))
+        });
+    if !config.outbound_interfaces.is_empty() {
+        let (outbound, other) = counted
+            .into_iter()
+            .partition(|interface| matches_any(&config.outbound_interfaces, interface));
+        counted = outbound;
+        excluded.extend(other);
+    }
+    if config.track_default_route_only {
+        let egress = connection::default_route_interfaces(config.multi_default_route);
+        if egress.is_empty() {
+            if !NO_DEFAULT_ROUTE_WARNED.swap(true, Ordering::SeqCst) {
+                log("Warning: there is no default route, counting all selected interfaces");
+            }
+        } else {
+            NO_DEFAULT_ROUTE_WARNED.store(false, Ordering::SeqCst);
+            let (egress, other) = counted
+                .into_iter()
+                .partition(|interface| egress.contains(interface));
+            counted = egress;
+            excluded.extend(other);
+        }
+    }
+    if let Some(wan) = wan::interface(config).filter(|wan| included.contains(wan)) {
+        excluded = included
+            .iter()
+            .filter(|interface| **interface != wan)
+            .cloned()
+            .collect();
+        counted = vec![wan];
+    }
+    let inbound = present
+        .into_iter()
+        .filter(|interface| {
+            matches_any(&config.inbound_interfaces, interface)
+                && !matches_any(&config.exclude_interfaces, interface)
+        })
+        .collect();
+    Selection {
+        included,
+        excluded,
+        counted,
+        inbound,
+    }
+}
+
+/// An interface is considered virtual if it isn't backed by a device, which sysfs indicates by
+/// the lack of a `/sys/class/net/<interface>/device` link. This covers `lo`, bridges, tunnels,
+/// veth pairs and the like.
+pub fn is_virtual(interface: &str) -> bool {
+    interface == "lo"
+        || !Path::new("/sys/class/net")
+            .join(interface)
+            .join("device")
+            .exists()
+}
+
+/// Line rate of `interface` in bytes per second, `None` if the driver doesn't report it
+/// (e.g. for wifi and virtual interfaces).
+pub fn speed_bytes(interface: &str) -> Option<u64> {
+    let raw = fs::read_to_string(Path::new("/sys/class/net").join(interface).join("speed")).ok()?;
+    // the speed is given in Mbit/s and is -1 if unknown
+    let mbits = raw.trim().parse::<i64>().ok().filter(|mbits| *mbits > 0)?;
+    Some(mbits as u64 * 1000 * 1000 / 8)
+}
+
+/// Interfaces whose traffic counts towards the limit.
+pub fn counted(config: &Config) -> Vec<String> {
+    select(config).counted
+}
+
+/// Matches `name` against a shell-style `pattern` supporting `*` and `?`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and the part of `name` it currently covers
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` swallow one more character
+                Some((star, covered)) => {
+                    p = star + 1;
+                    n = covered + 1;
+                    backtrack = Some((star, covered + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_match, select_among};
+    use crate::{fixtures::config, Config};
+
+    fn present() -> Vec<String> {
+        ["eth0", "eth1", "lo", "wg0", "wlan0"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    #[test]
+    fn selects_the_directions_separately() {
+        let config = Config {
+            outbound_interfaces: vec!["eth*".to_string()],
+            inbound_interfaces: vec!["wg0".to_string(), "wlan*".to_string()],
+            exclude_interfaces: vec!["eth1".to_string()],
+            ..config()
+        };
+        let selection = select_among(&config, present(), |interface| interface == "lo");
+        assert_eq!(selection.counted, ["eth0"]);
+        assert_eq!(selection.inbound, ["wg0", "wlan0"]);
+        assert_eq!(selection.excluded, ["eth1", "lo", "wg0", "wlan0"]);
+    }
+
+    #[test]
+    fn leaves_out_virtual_interfaces() {
+        let config = Config {
+            count_virtual_interfaces: false,
+            ..config()
+        };
+        let selection = select_among(&config, present(), |interface| {
+            interface == "lo" || interface == "wg0"
+        });
+        assert_eq!(selection.counted, ["eth0", "eth1", "wlan0"]);
+        assert!(selection.inbound.is_empty());
+    }
+
+    #[test]
+    fn matches_any_run_of_characters_with_a_star() {
+        assert!(glob_match("eth*", "eth0"));
+        assert!(glob_match("eth*", "eth"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*0", "wlan0"));
+        assert!(glob_match("w*n*", "wwan0"));
+        assert!(!glob_match("eth*", "veth0"));
+        assert!(!glob_match("*0", "wlan1"));
+    }
+
+    #[test]
+    fn matches_a_single_character_with_a_question_mark() {
+        assert!(glob_match("eth?", "eth0"));
+        assert!(glob_match("w?an0", "wlan0"));
+        assert!(!glob_match("eth?", "eth"));
+        assert!(!glob_match("eth?", "eth10"));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn backtracks_to_the_last_star() {
+        // the first `b` isn't the one the pattern ends with
+        assert!(glob_match("a*b", "aXbb"));
+        assert!(glob_match("a*b*c", "aXbYbc"));
+        assert!(!glob_match("a*b", "aXbX"));
+        assert!(!glob_match("eth0", "eth1"));
+        assert!(!glob_match("", "eth0"));
+    }
+}
@@ -0,0 +1,80 @@
+//! Persistent all-time byte count which survives reboots and interface resets.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use num_bigint::BigUint;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::counter;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Ledger {
+    /// all bytes counted since the ledger was created, i.e. sent on the counted interfaces and
+    /// received on the inbound interfaces
+    pub lifetime_bytes: String,
+    /// last counter value seen per interface, interfaces which disappeared are kept
+    /// so that they are picked up correctly once they come back
+    pub last_seen: BTreeMap<String, u64>,
+}
+
+impl Ledger {
+    /// Adds the positive delta of every interface counter since the last call, a counter seen
+    /// for the first time only counts from now on.
+    pub fn record(&mut self, counters: &[(String, u64)]) {
+        let mut total = self.lifetime();
+        for (interface, curr) in counters {
+            total += counter::delta(self.last_seen.get(interface).copied(), *curr);
+            self.last_seen.insert(interface.clone(), *curr);
+        }
+        self.lifetime_bytes = total.to_string();
+    }
+
+    pub fn lifetime(&self) -> BigUint {
+        BigUint::from_str(&self.lifetime_bytes).unwrap_or(BigUint::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use super::Ledger;
+    use crate::fixtures::counters;
+
+    #[test]
+    fn ignores_traffic_from_before_tracking() {
+        let mut ledger = Ledger::default();
+        ledger.record(&counters(&[("eth0", 1000)]));
+        ledger.record(&counters(&[("eth0", 1500)]));
+        assert_eq!(ledger.lifetime(), BigUint::from(500u32));
+    }
+
+    #[test]
+    fn counts_everything_after_a_reboot() {
+        let mut ledger = Ledger::default();
+        ledger.record(&counters(&[("eth0", 1000), ("eth0:rx", 300)]));
+        ledger.record(&counters(&[("eth0", 1200), ("eth0:rx", 400)]));
+        // the counters start over from zero mid-window
+        ledger.record(&counters(&[("eth0", 50), ("eth0:rx", 10)]));
+        ledger.record(&counters(&[("eth0", 80), ("eth0:rx", 10)]));
+        assert_eq!(ledger.lifetime(), BigUint::from(200u32 + 100 + 80 + 10));
+    }
+
+    #[test]
+    fn keeps_disappeared_interfaces() {
+        let mut ledger = Ledger::default();
+        ledger.record(&counters(&[("eth0", 100), ("usb0", 100)]));
+        ledger.record(&counters(&[("eth0", 200), ("usb0", 150)]));
+        ledger.record(&counters(&[("eth0", 300)]));
+        assert_eq!(ledger.last_seen.get("usb0"), Some(&150));
+        // the interface came back with its counter intact
+        ledger.record(&counters(&[("eth0", 300), ("usb0", 170)]));
+        // an interface appearing later only counts from then on
+        ledger.record(&counters(&[("eth0", 300), ("usb0", 170), ("wwan0", 40)]));
+        ledger.record(&counters(&[("eth0", 300), ("usb0", 170), ("wwan0", 70)]));
+        assert_eq!(
+            ledger.lifetime(),
+            BigUint::from(100u32 + 50 + 100 + 20 + 30)
+        );
+    }
+}
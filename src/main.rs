@@ -1,51 +1,172 @@
 #![feature(duration_constructors)]
 
-use chrono::Local;
+use chrono::{Local, Timelike};
 use num_bigint::{BigUint, ToBigUint};
 use std::{
-    fs,
-    path::PathBuf,
-    process::Command,
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process,
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
     thread,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use alerts::AlertTier;
+use anomaly::SpikeDetector;
+use check::Outcome;
+use connection::MultiDefaultRoute;
+use counter::Counter;
+use events::Event;
+use history::WindowRecord;
+use ledger::Ledger;
 use serde_derive::{Deserialize, Serialize};
+use shaping::{
+    disable_lowered_bandwidth, enable_lower_bandwidth, Enforcement, InterfacePolicy, ThrottleSplit,
+};
+
+mod alerts;
+mod anomaly;
+mod calibration;
+mod check;
+mod commands;
+mod connection;
+mod control;
+mod counter;
+mod crypto;
+mod duration;
+mod events;
+#[cfg(test)]
+mod fixtures;
+mod forecast;
+mod history;
+mod interfaces;
+mod ledger;
+mod policy;
+mod replay;
+mod shaping;
+mod signals;
+#[cfg(feature = "snmp")]
+mod snmp;
+mod updates;
+mod wan;
+mod wireguard;
 
 fn main() {
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+    if let Some(pos) = args.iter().position(|arg| arg == "--no-persist") {
+        args.remove(pos);
+        EPHEMERAL.store(true, Ordering::SeqCst);
+    }
+    if let Some(pos) = args.iter().position(|arg| arg == "--no-default-config") {
+        args.remove(pos);
+        NO_DEFAULT_CONFIG.store(true, Ordering::SeqCst);
+    }
+    if take_config_stdin_flag(&mut args) {
+        Config::read_stdin();
+    }
+    if !args.is_empty() {
+        commands::run(&args);
+        return;
+    }
+    let mut config = Config::load();
+    init_log(&config);
+    if config.ephemeral {
+        EPHEMERAL.store(true, Ordering::SeqCst);
+    }
+    if ephemeral() {
+        log("Running ephemeral, neither the state nor the history get persisted");
+    }
+    if let Some(interface) = config.wireguard_interface.take() {
+        match wireguard::transfer(&interface) {
+            Ok(_) => config.wireguard_interface = Some(interface),
+            Err(err) => log(&format!(
+                "Error: can't count the WireGuard peers of {interface}, counting the interfaces instead: {err}"
+            )),
+        }
+    }
+    let config = Arc::new(config);
+    if let Err(err) = crypto::init(&config) {
+        log(&err);
+        process::exit(1);
+    }
     // handle cases in which the machine was restarted and thus byte counts got reset
     let meta = {
-        let mut meta = Meta::load();
-        if BigUint::from_str(&meta.starting_bytes).unwrap() > fetch_outbound_bytes() {
-            meta.starting_bytes = "0".to_string();
-            meta.store();
+        let mut meta = Meta::load(&config);
+        meta.applied_rate = config.for_connection(None).lower_limit_bytes;
+        // the counter picks up the traffic sent while not running, also across reboots
+        meta.last_saved_bytes = meta.sample(&config).to_string();
+        meta.store();
+        meta.restore_overrides();
+        meta.calibrated_correction = calibration::factor();
+        if let Some(rate) = config
+            .startup_fallback_rate_bytes
+            .filter(|_| meta.throttled)
+        {
+            log(&format!(
+                "Was throttled before the restart, applying the fallback limit of {rate} bytes until the usage got evaluated..."
+            ));
+            // the limitation may have survived the restart
+            disable_lowered_bandwidth(&config);
+            if enable_lower_bandwidth(rate, &config, &BTreeMap::new()).is_ok() {
+                meta.applied_rate = rate;
+            } else {
+                meta.set_throttled(false);
+            }
+        } else if meta.throttled {
+            // a reboot removed the limitation, a restart of the daemon alone may have kept it, so
+            // start out unlimited and let the first check apply it again
+            disable_lowered_bandwidth(&config);
+            meta.set_throttled(false);
         }
         meta
     };
     let meta = Arc::new(Mutex::new(meta));
-    let config = Config::load();
     let save_ms = config.save_interval_ms;
     let meta2 = meta.clone();
+    let config2 = config.clone();
     log("Started up traffic limiter...");
+    signals::install();
+    if let Some(timeout_ms) = config.drain_timeout_ms {
+        signals::install_interrupt();
+        let meta = meta.clone();
+        let config = config.clone();
+        // drain thread
+        thread::spawn(move || {
+            while !signals::interrupted() {
+                thread::sleep(Duration::from_millis(DRAIN_POLL_MS));
+            }
+            drain(&meta, &config, timeout_ms);
+        });
+    }
+    control::spawn(meta.clone(), config.clone());
     // interval saver thread
     thread::spawn(move || {
         let meta = meta2;
+        let config = config2;
         loop {
             let mut meta = meta.lock().unwrap();
-            meta.last_saved_bytes = fetch_outbound_bytes().to_string();
+            meta.last_saved_bytes = meta.sample(&config).to_string();
             meta.store();
+            let now = current_time_millis() as u64;
+            meta.timers.save_last_ms = now;
+            meta.timers.save_next_ms = now + save_ms;
             drop(meta);
             thread::sleep(Duration::from_millis(save_ms));
         }
     });
     let meta2 = meta.clone();
+    let config2 = config.clone();
     let reset_delay = config.capture_timeframe_ms;
-    let max_bytes = config.max_bytes;
     // limit resetter thread
     thread::spawn(move || {
         let meta = meta2;
+        let config = config2;
         loop {
             let c_meta = meta.lock().unwrap();
             let sleep_ms = c_meta
@@ -54,21 +175,175 @@ fn main() {
             drop(c_meta);
             thread::sleep(Duration::from_millis(sleep_ms));
             let mut meta = meta.lock().unwrap();
-            let dist = BigUint::from_str(&meta.last_saved_bytes).unwrap()
-                - BigUint::from_str(&meta.starting_bytes).unwrap();
-            if dist > max_bytes.to_biguint().unwrap() {
-                disable_lowered_bandwidth();
+            // the sleep may have ended early or `reset_at_ms` may have changed in the meantime
+            if (current_time_millis() as u64) < meta.reset_at_ms {
+                continue;
+            }
+            if meta.throttled {
+                disable_lowered_bandwidth(&config);
+                meta.set_throttled(false);
             }
-            meta.starting_bytes = fetch_outbound_bytes().to_string();
+            let curr_bytes = meta.sample(&config);
+            let usage = Usage::compute(&meta, &curr_bytes, &config.for_connection(meta.connection));
+            let record = WindowRecord {
+                ended_at_ms: current_time_millis() as u64,
+                used_bytes: usage.used_bytes,
+                off_peak_bytes: usage.off_peak_bytes,
+                throttled_ms: usage.throttled_ms,
+                window_ms: reset_delay,
+                hourly_bytes: std::mem::take(&mut meta.hourly_bytes),
+                interface_bytes: std::mem::take(&mut meta.interface_bytes),
+            };
+            // the placeholder only waits for the first real window, it isn't one itself
+            let placeholder = meta.reset_at_ms == PLACEHOLDER_RESET_AT_MS;
+            if !placeholder {
+                history::append(&record);
+            }
+            meta.throttled_ms = 0;
+            meta.fired_alert_tiers.clear();
+            // the allowance starts out full again
+            meta.burst_credit_bytes = 0.0;
+            meta.burst_excess_bytes = 0.0;
+            meta.burst_refilled_at_ms = 0;
+            // the initial usage belongs to the first real window
+            if !placeholder {
+                meta.usage_offset_bytes = 0;
+            }
+            meta.inbound_bytes = 0;
+            meta.outbound_bytes = 0;
+            meta.starting_bytes = curr_bytes.to_string();
+            meta.off_peak_bytes = "0".to_string();
             meta.reset_at_ms = current_time_millis() as u64 + reset_delay;
+            meta.timers.reset_last_ms = current_time_millis() as u64;
+            events::publish(Event::Reset {
+                reset_at_ms: meta.reset_at_ms,
+            });
             meta.store();
         }
     });
+    if config.reconcile_every_n_checks != 0 {
+        let meta = meta.clone();
+        let config = config.clone();
+        // reconciliation thread
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(
+                config.check_interval_ms * config.reconcile_every_n_checks,
+            ));
+            let mut meta = meta.lock().unwrap();
+            let config = config.for_connection(meta.connection);
+            shaping::reconcile(&mut meta, &config);
+        });
+    }
+    let mut enforcing = config.enforcing_at(current_time_millis() as u64);
+    if let Some(start) = config.enforcement_start_at_ms.filter(|_| !enforcing) {
+        log(&format!(
+            "Trial period: only observing traffic until {}",
+            format_millis(start)
+        ));
+    }
+    let mut observed_over_cap = false;
+    let mut policy_reachable = true;
+    // counter reading, time and window end of the previous check
+    let mut last_sample: Option<(BigUint, u64, u64)> = None;
+    // other threads sample the counters as well, so the per-interface deltas need their own
+    let mut last_counters = BTreeMap::new();
+    let mut spikes = SpikeDetector::default();
+    // counter reading and time at which the limitation got applied, see `verify_throttle`
+    let mut verifying: Option<(BigUint, u64)> = None;
+    let base_config = config;
+    let mut connection = None;
+    let mut config = base_config.for_connection(connection);
     // byte amount saver
     loop {
-        let curr_bytes = fetch_outbound_bytes();
-        let meta = meta.lock().unwrap();
-        let starting = BigUint::from_str(&meta.starting_bytes).unwrap();
+        if !base_config.connection_profiles.is_empty() {
+            let detected = connection::detect();
+            if detected != connection {
+                log(&format!(
+                    "Connection changed to {}",
+                    detected.unwrap_or("none")
+                ));
+                connection = detected;
+                config = base_config.for_connection(connection);
+                meta.lock().unwrap().connection = connection;
+            }
+        }
+        if signals::take_panic_request() {
+            panic_reset(&mut meta.lock().unwrap(), &config);
+        }
+        let updates_running = config.pause_during_updates && updates::running();
+        follow_updates(&mut meta.lock().unwrap(), updates_running, &config);
+        let previous = meta.lock().unwrap().ledger.last_seen.clone();
+        let counters = fetch_interface_bytes_checked(&config, &previous);
+        // the order of the counters isn't stable, e.g. for WireGuard peers
+        let counted = counters
+            .iter()
+            .map(|(counter, _)| counter)
+            .collect::<BTreeSet<_>>();
+        if !last_counters.is_empty() && !last_counters.keys().eq(counted.iter().copied()) {
+            // e.g. the WAN interface got rediscovered, see `Counter::advance`
+            log(&format!(
+                "Counted interfaces changed to {counted:?}, the usage counted so far carries over"
+            ));
+        }
+        let mut c_meta = meta.lock().unwrap();
+        let curr_bytes = c_meta.advance(&counters, &config);
+        c_meta.timers.check_last_ms = current_time_millis() as u64;
+        c_meta.account_throttled();
+        let sample_ms = current_time_millis() as u64;
+        let rolled_over = c_meta.roll_over_day(sample_ms);
+        // the traffic since the previous check mustn't land in the new day or window as a whole,
+        // the previous check may have been hours ago when throttled
+        let window = c_meta.reset_at_ms;
+        if let Some((last, last_ms, _)) = last_sample.filter(|(last, _, last_window)| {
+            curr_bytes >= *last && !rolled_over && *last_window == window
+        }) {
+            let delta = &curr_bytes - last;
+            c_meta.account(&delta, sample_ms, Local::now().hour(), &config);
+            let elapsed_secs = sample_ms.saturating_sub(last_ms).max(1) as f64 / 1000.0;
+            let rate = u64::try_from(delta).unwrap_or(u64::MAX) as f64 / elapsed_secs;
+            c_meta.smoothed_rate =
+                RATE_SMOOTHING * rate + (1.0 - RATE_SMOOTHING) * c_meta.smoothed_rate;
+            for (interface, bytes) in &counters {
+                if let Some(prev) = last_counters.get(interface).filter(|prev| bytes >= prev) {
+                    if interface.ends_with(RX_SUFFIX) {
+                        c_meta.inbound_bytes += bytes - prev;
+                    } else {
+                        c_meta.outbound_bytes += bytes - prev;
+                    }
+                    let total = c_meta.interface_bytes.entry(interface.clone()).or_default();
+                    *total = total.saturating_add(bytes - prev);
+                    let rate = (bytes - prev) as f64 / elapsed_secs;
+                    let smoothed = c_meta
+                        .interface_rates
+                        .entry(interface.clone())
+                        .or_insert(rate);
+                    *smoothed = RATE_SMOOTHING * rate + (1.0 - RATE_SMOOTHING) * *smoothed;
+                }
+            }
+        }
+        last_sample = Some((curr_bytes.clone(), sample_ms, window));
+        last_counters = counters.into_iter().collect();
+        if let Some(multiplier) = config.spike_multiplier {
+            if let Some(baseline) = spikes.check(
+                sample_ms,
+                c_meta.smoothed_rate,
+                config.spike_baseline_window_ms,
+                multiplier,
+            ) {
+                report_spike(&c_meta, baseline, &config);
+            }
+        }
+        let verify_after_ms = VERIFY_THROTTLE_CHECKS * config.check_interval_ms;
+        if let Some((start, start_ms)) =
+            verifying.take_if(|(_, start_ms)| sample_ms >= *start_ms + verify_after_ms)
+        {
+            if c_meta.throttled && curr_bytes >= start {
+                let sent = u64::try_from(&curr_bytes - start).unwrap_or(u64::MAX);
+                let elapsed_secs = sample_ms.saturating_sub(start_ms).max(1) as f64 / 1000.0;
+                verify_throttle(&c_meta, sent as f64 / elapsed_secs, &config);
+            }
+        }
+        let starting = BigUint::from_str(&c_meta.starting_bytes).unwrap();
         let dist = if curr_bytes > starting {
             curr_bytes.clone() - starting
         } else {
@@ -76,175 +351,1488 @@ fn main() {
         };
         if dist >= config.save_every_n_bytes.to_biguint().unwrap() {
             Meta {
-                reset_at_ms: meta.reset_at_ms,
-                starting_bytes: meta.starting_bytes.clone(),
                 last_saved_bytes: curr_bytes.to_string(),
+                ..c_meta.clone()
             }
             .store();
         }
-        if dist > config.max_bytes.to_biguint().unwrap() {
-            enable_lower_bandwidth(
-                config.lower_limit_bytes,
-                config.burst_buffer_size,
-                config.buffer_latency_ms,
-            );
-            let sleep_time = meta
-                .reset_at_ms
-                .saturating_sub(current_time_millis() as u64);
-            drop(meta);
-            thread::sleep(Duration::from_millis(sleep_time));
-        } else {
-            drop(meta);
+        let now = current_time_millis() as u64;
+        if !enforcing && config.enforcing_at(now) {
+            log("Trial period is over, enforcing the limit from now on...");
+            enforcing = true;
+        }
+        let usage = Usage::compute(&c_meta, &curr_bytes, &config);
+        let over_cap = check::over_cap(&mut c_meta, &usage, now, &config);
+        events::publish(Event::Tick { usage: &usage });
+        alerts::check(&mut c_meta, &usage, &config);
+        if signals::take_status_request() {
+            log(&format!(
+                "Status: {curr_bytes} bytes counted in total, {} of {} bytes used, {} remaining, sending {:.0} bytes per second, {}",
+                usage.used_bytes,
+                usage.max_bytes,
+                usage.remaining_bytes,
+                usage.smoothed_rate_bytes_per_sec,
+                if usage.throttled { "throttled" } else { "not throttled" }
+            ));
+        }
+        // a slow policy service mustn't block the other threads
+        drop(c_meta);
+        let external =
+            config.external_policy_socket.as_ref().and_then(|path| {
+                match policy::query(
+                    path,
+                    config.external_policy_timeout_ms,
+                    &usage,
+                    config.lower_limit_bytes,
+                ) {
+                    Ok(decision) => {
+                        if !policy_reachable {
+                            log("External policy is reachable again");
+                            policy_reachable = true;
+                        }
+                        Some(decision)
+                    }
+                    Err(err) => {
+                        if policy_reachable {
+                            log(&format!(
+                            "External policy unreachable, falling back to built-in policy: {err}"
+                        ));
+                            policy_reachable = false;
+                        }
+                        None
+                    }
+                }
+            });
+        let mut c_meta = meta.lock().unwrap();
+        let decision =
+            external.unwrap_or_else(|| check::built_in(&c_meta, &usage, over_cap, now, &config));
+        let outcome = check::apply(
+            &mut c_meta,
+            decision,
+            over_cap,
+            now,
+            &mut observed_over_cap,
+            &config,
+            &mut check::Tc,
+        );
+        match outcome {
+            Outcome::Trial { rate, first: true } => log(&format!(
+                "Trial period: would limit network traffic to {rate} bytes now"
+            )),
+            Outcome::Limited {
+                rate,
+                previous,
+                reshared,
+            } => {
+                if reshared {
+                    log("Usage shares of the interfaces shifted, split the limit anew");
+                }
+                if let Some(previous) = previous.filter(|previous| *previous != rate) {
+                    log(&format!(
+                        "Refined the limitation from {previous} to {rate} bytes"
+                    ));
+                }
+                c_meta.store();
+                // netem doesn't limit the rate and unmatched DSCP values aren't limited at all
+                if config.verify_throttle
+                    && config.enforcement != Enforcement::Netem
+                    && config.throttle_dscp_match.is_empty()
+                {
+                    verifying = Some((curr_bytes.clone(), now));
+                }
+            }
+            Outcome::Released => c_meta.store(),
+            _ => {}
         }
+        drop(c_meta);
+        // the external policy, the usage shares and the pace have to be consulted on every
+        // check, the verification has to measure the next few checks
+        if matches!(outcome, Outcome::Limited { .. } | Outcome::Kept)
+            && config.external_policy_socket.is_none()
+            && config.throttle_split != ThrottleSplit::Proportional
+            && config.enforcement != Enforcement::PaceToReset
+            && verifying.is_none()
+        {
+            let step_ms = match config.max_sleep_chunk_ms {
+                0 => config.check_interval_ms,
+                chunk_ms => config.check_interval_ms.min(chunk_ms),
+            };
+            sleep_while_throttled(&meta, step_ms, config.max_sleep_chunk_ms, || {
+                (!base_config.connection_profiles.is_empty() && connection::detect() != connection)
+                    || (config.daily_max_bytes.is_some()
+                        && current_time_millis() as u64 >= meta.lock().unwrap().daily_reset_at_ms)
+            });
+        }
+        meta.lock().unwrap().timers.check_next_ms =
+            current_time_millis() as u64 + config.check_interval_ms;
         thread::sleep(Duration::from_millis(config.check_interval_ms));
     }
 }
 
-fn enable_lower_bandwidth(limit: u64, burst_buffer_size: u64, buffer_latency_ms: u64) {
-    log(&format!("Limiting network traffic to {limit} bytes..."));
-    for interface in fs::read_dir("/sys/class/net").unwrap() {
-        if let Ok(interface) = interface {
-            if let Err(err) = Command::new("sudo")
-                .args([
-                    "tc",
-                    "qdisc",
-                    "add",
-                    "dev",
-                    interface.file_name().to_string_lossy().as_ref(),
-                    "root",
-                    "tbf",
-                    "rate",
-                    (limit * 8).to_string().as_str(),
-                    "burst",
-                    &burst_buffer_size.to_string(),
-                    "latency",
-                    &buffer_latency_ms.to_string(),
-                ])
-                .spawn()
-                .unwrap()
-                .wait()
-            {
-                log(&format!("Error applying restriction: {err}"));
+/// set when the usage or the cap changed outside of the main loop, see [`reevaluate`]
+static REEVALUATE: AtomicBool = AtomicBool::new(false);
+
+/// Cuts [`sleep_while_throttled`] short so that the next check decides whether the limitation
+/// still has to apply.
+fn reevaluate() {
+    REEVALUATE.store(true, Ordering::SeqCst);
+}
+
+/// Sleeps until the window resets, waking up every `step_ms` so that a panic reset,
+/// [`reevaluate`] or `interrupted` returning true can cut the sleep short. Progress gets logged and published
+/// every `progress_ms` so that the daemon doesn't look hung.
+fn sleep_while_throttled(
+    meta: &Mutex<Meta>,
+    step_ms: u64,
+    progress_ms: u64,
+    interrupted: impl Fn() -> bool,
+) {
+    let mut last_progress_ms = current_time_millis() as u64;
+    loop {
+        let sleep_ms = {
+            let mut meta = meta.lock().unwrap();
+            if !meta.throttled {
+                return;
+            }
+            meta.account_throttled();
+            let now = current_time_millis() as u64;
+            let sleep_ms = meta.reset_at_ms.saturating_sub(now).min(step_ms);
+            meta.timers.check_next_ms = now + sleep_ms;
+            if progress_ms != 0 && now >= last_progress_ms + progress_ms {
+                last_progress_ms = now;
+                let remaining_ms = meta.reset_at_ms.saturating_sub(now);
+                log(&format!(
+                    "Throttled, {:.1} hours until the window resets",
+                    remaining_ms as f64 / (1000.0 * 60.0 * 60.0)
+                ));
+                events::publish(Event::Throttled {
+                    reset_at_ms: meta.reset_at_ms,
+                    remaining_ms,
+                });
             }
+            sleep_ms
+        };
+        if sleep_ms == 0
+            || signals::panic_requested()
+            || signals::status_requested()
+            || REEVALUATE.swap(false, Ordering::SeqCst)
+            || interrupted()
+        {
+            return;
         }
+        thread::sleep(Duration::from_millis(sleep_ms));
     }
 }
 
-fn disable_lowered_bandwidth() {
-    log("Loosening network traffic restrictions...");
-    for interface in fs::read_dir("/sys/class/net").unwrap() {
-        if let Ok(interface) = interface {
-            if let Err(err) = Command::new("sudo")
-                .args([
-                    "tc",
-                    "qdisc",
-                    "del",
-                    "dev",
-                    interface.file_name().to_string_lossy().as_ref(),
-                    "root",
-                ])
-                .spawn()
-                .unwrap()
-                .wait()
-            {
-                log(&format!("Error loosening restriction: {err}"));
-            }
+/// the achieved rate gets measured over this many checks after applying the limitation
+const VERIFY_THROTTLE_CHECKS: u64 = 3;
+
+/// how far the measured rate may exceed the limit before the limitation counts as ineffective
+const VERIFY_THROTTLE_TOLERANCE: f64 = 1.5;
+
+/// Alerts if the rate measured since applying the limitation is well above the limit, which
+/// means shaping isn't working, e.g. because it got applied to the wrong interface.
+fn verify_throttle(meta: &Meta, measured_rate: f64, config: &Config) {
+    let target_rate = if config.throttle_split == ThrottleSplit::PerInterface {
+        // every counted interface may send at the full rate
+        meta.applied_rate * interfaces::counted(config).len().max(1) as u64
+    } else {
+        meta.applied_rate
+    };
+    if measured_rate <= target_rate as f64 * VERIFY_THROTTLE_TOLERANCE {
+        log(&format!(
+            "Limitation verified: sending {measured_rate:.0} bytes per second, limit is {target_rate}"
+        ));
+        return;
+    }
+    log(&format!(
+        "Warning: limitation seems ineffective, sending {measured_rate:.0} bytes per second although limited to {target_rate}"
+    ));
+    events::publish(Event::ThrottleIneffective {
+        target_rate,
+        measured_rate,
+    });
+    shaping::run_hooks("anomaly", &config.anomaly_commands);
+}
+
+fn report_spike(meta: &Meta, baseline: f64, config: &Config) {
+    let top_interface = meta
+        .interface_rates
+        .iter()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(interface, _)| interface.clone());
+    log(&format!(
+        "Anomaly: sending {:.0} bytes per second, the trailing average is {baseline:.0}{}",
+        meta.smoothed_rate,
+        top_interface
+            .as_ref()
+            .map(|interface| format!(", most of it via {interface}"))
+            .unwrap_or_default()
+    ));
+    events::publish(Event::Anomaly {
+        rate: meta.smoothed_rate,
+        baseline,
+        top_interface,
+    });
+    shaping::run_hooks("anomaly", &config.anomaly_commands);
+}
+
+/// Break-glass recovery: removes all restrictions right away and pauses enforcement for
+/// `panic_cooldown_ms` so that the operator can fix whatever made the host unusable.
+fn panic_reset(meta: &mut Meta, config: &Config) {
+    let cooldown_ms = config.panic_cooldown_ms;
+    log("!!! PANIC RESET: removing all network restrictions !!!");
+    disable_lowered_bandwidth(config);
+    meta.set_throttled(false);
+    let now = current_time_millis() as u64;
+    meta.pause = Some(Pause {
+        started_at_ms: now,
+        until_ms: Some(now + cooldown_ms),
+        gates_baseline: false,
+    });
+    events::publish(Event::PanicReset {
+        paused_until_ms: now + cooldown_ms,
+    });
+    meta.store();
+    log(&format!(
+        "!!! PANIC RESET: enforcement paused for {cooldown_ms}ms !!!"
+    ));
+}
+
+/// Pauses counting while package manager updates run and resumes once they finished, see
+/// `pause_during_updates`. A pause set otherwise is left alone.
+fn follow_updates(meta: &mut Meta, running: bool, config: &Config) {
+    let now = current_time_millis() as u64;
+    let updates_pause = meta
+        .pause
+        .as_ref()
+        .is_some_and(|pause| pause.until_ms.is_none());
+    if running && !meta.paused(now) {
+        log("Package manager updates detected, pausing counting...");
+        if meta.throttled {
+            disable_lowered_bandwidth(config);
+            meta.set_throttled(false);
+        }
+        meta.pause = Some(Pause {
+            started_at_ms: now,
+            until_ms: None,
+            gates_baseline: true,
+        });
+        meta.store();
+    } else if !running && updates_pause {
+        log("Package manager updates finished, counting again");
+        meta.pause = None;
+        meta.store();
+    }
+}
+
+const DRAIN_POLL_MS: u64 = 100;
+
+/// Shuts down cleanly after `SIGTERM`: every change of the limitation happens while holding
+/// the meta lock, so once we hold it no `tc` command is in flight and no new one gets started.
+fn drain(meta: &Mutex<Meta>, config: &Config, timeout_ms: u64) -> ! {
+    log(&format!(
+        "Draining before shutdown, waiting up to {timeout_ms}ms for running limitation changes..."
+    ));
+    let deadline = current_time_millis() as u64 + timeout_ms;
+    let mut waiting = false;
+    loop {
+        if let Ok(mut meta) = meta.try_lock() {
+            meta.last_saved_bytes = meta.sample(config).to_string();
+            meta.flush();
+            log("Drained, state flushed, exiting");
+            process::exit(0);
+        }
+        if current_time_millis() as u64 >= deadline {
+            log(
+                "Drain timeout reached while a limitation change was still running, exiting anyway",
+            );
+            process::exit(1);
         }
+        if !waiting {
+            log("Waiting for a running limitation change to finish...");
+            waiting = true;
+        }
+        thread::sleep(Duration::from_millis(DRAIN_POLL_MS));
+    }
+}
+
+/// Multiplier of the traffic of `counter`, see `interface_weights`.
+fn weight(config: &Config, counter: &str) -> f64 {
+    let interface = counter.strip_suffix(RX_SUFFIX).unwrap_or(counter);
+    config
+        .interface_weights
+        .get(interface)
+        .copied()
+        .unwrap_or(1.0)
+}
+
+/// Reads the sent bytes of every counted interface and the received bytes of every inbound
+/// interface, the latter named `<interface>:rx`.
+fn fetch_interface_bytes(config: &Config) -> Vec<(String, u64)> {
+    if let Some(interface) = &config.wireguard_interface {
+        return wireguard::fetch(interface, config);
+    }
+    let selection = interfaces::select(config);
+    selection
+        .counted
+        .into_iter()
+        .chain(
+            selection
+                .inbound
+                .into_iter()
+                .map(|interface| interface + RX_SUFFIX),
+        )
+        .map(|counter| {
+            let bytes = read_counter(&counter);
+            (counter, bytes)
+        })
+        .collect()
+}
+
+/// marks the counters of received bytes, see [`fetch_interface_bytes`]
+const RX_SUFFIX: &str = ":rx";
+
+fn read_counter(counter: &str) -> u64 {
+    let (interface, statistic) = match counter.strip_suffix(RX_SUFFIX) {
+        Some(interface) => (interface, "rx_bytes"),
+        None => (counter, "tx_bytes"),
+    };
+    let raw =
+        fs::read_to_string(format!("/sys/class/net/{interface}/statistics/{statistic}")).unwrap();
+    // the last character isn't part of the number, so ignore it.
+    u64::from_str(&raw[0..(raw.len() - 1)]).unwrap()
+}
+
+/// Like [`fetch_interface_bytes`], but an interface whose counter dropped to exactly zero is
+/// likely just reinitializing, so it gets read once more after a short delay and the zero is only
+/// accepted if it's confirmed, see [`recheck_zeros`].
+fn fetch_interface_bytes_checked(
+    config: &Config,
+    previous: &BTreeMap<String, u64>,
+) -> Vec<(String, u64)> {
+    let mut counters = fetch_interface_bytes(config);
+    // WireGuard peers can't be reread one by one
+    if config.wireguard_interface.is_none() {
+        recheck_zeros(&mut counters, previous, read_counter);
     }
+    counters
 }
 
-fn fetch_outbound_bytes() -> BigUint {
-    let mut sum = BigUint::ZERO;
-    for interface in fs::read_dir("/sys/class/net").unwrap() {
-        if let Ok(interface) = interface {
-            let mut path = interface.path();
-            path.push("statistics");
-            path.push("tx_bytes");
-            let raw = fs::read_to_string(path.clone()).unwrap();
-            // the last character isn't part of the number, so ignore it.
-            sum += BigUint::from_str(&raw[0..(raw.len() - 1)]).unwrap();
+/// Rereads the counters which dropped to zero with `read` after a short delay. With several
+/// interfaces all of them being reset together is taken for a genuine reset, a single interface
+/// always gets reread.
+fn recheck_zeros(
+    counters: &mut [(String, u64)],
+    previous: &BTreeMap<String, u64>,
+    read: impl Fn(&str) -> u64,
+) {
+    let was_active = |interface: &str| previous.get(interface).is_some_and(|last| *last > 0);
+    let active = counters
+        .iter()
+        .filter(|(interface, _)| was_active(interface));
+    let (suspects, active) = active.fold((0, 0), |(suspects, active), (_, bytes)| {
+        (suspects + (*bytes == 0) as usize, active + 1)
+    });
+    if suspects == 0 || (suspects == active && active > 1) {
+        return;
+    }
+    thread::sleep(Duration::from_millis(SPURIOUS_ZERO_RETRY_DELAY_MS));
+    for (interface, bytes) in counters.iter_mut() {
+        if *bytes == 0 && was_active(interface) {
+            *bytes = read(interface);
+            if *bytes != 0 {
+                log(&format!("Ignored spurious zero counter of {interface}"));
+            }
         }
     }
-    sum
 }
 
-#[derive(Serialize, Deserialize)]
+const SPURIOUS_ZERO_RETRY_DELAY_MS: u64 = 250;
+
+#[derive(Serialize, Deserialize, Clone)]
 struct Config {
+    #[serde(deserialize_with = "duration::deserialize_ms")]
     save_interval_ms: u64,
+    #[serde(deserialize_with = "duration::deserialize_ms")]
     check_interval_ms: u64,
     save_every_n_bytes: u64,
+    /// writes of the state sooner than this after the previous one get dropped to spare flash
+    /// storage, at the cost of losing up to this much accounting on a crash
+    #[serde(default, deserialize_with = "duration::deserialize_ms")]
+    min_store_interval_ms: u64,
+    #[serde(deserialize_with = "duration::deserialize_ms")]
     capture_timeframe_ms: u64,
-    max_bytes: u64,
+    /// may be left out if `max_bytes_file` is set, one of both is required
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<u64>,
+    /// file containing `max_bytes`, which takes precedence over the inline value, see
+    /// [`credential_path`]
+    #[serde(default)]
+    max_bytes_file: Option<PathBuf>,
+    /// rate traffic gets limited to in bytes per second, 0 derives it from the line rate,
+    /// see [`shaping::auto_rate`]
     lower_limit_bytes: u64,
     burst_buffer_size: u64,
     buffer_latency_ms: u64,
+    /// applied right away on startup if the limitation was applied before, until the usage got
+    /// evaluated and the actual limit is known
+    #[serde(default)]
+    startup_fallback_rate_bytes: Option<u64>,
+    /// used with `lower_limit_bytes` set to 0 if no interface reports its line rate
+    #[serde(default = "default_auto_rate_fallback_bytes")]
+    auto_rate_fallback_bytes: u64,
+    /// if set only packets with one of these DSCP values get limited, the rest stays unshaped
+    #[serde(default)]
+    throttle_dscp_match: Vec<u8>,
+    /// how long enforcement stays paused after a panic reset
+    #[serde(
+        default = "default_panic_cooldown_ms",
+        deserialize_with = "duration::deserialize_ms"
+    )]
+    panic_cooldown_ms: u64,
+    /// until this point in time the limit is only observed and would-be actions get logged
+    #[serde(default)]
+    enforcement_start_at_ms: Option<u64>,
+    /// socket of an external service which decides whether to limit the traffic, see [`policy`]
+    #[serde(default)]
+    external_policy_socket: Option<PathBuf>,
+    /// how long to wait for the external policy, has to be positive
+    #[serde(
+        default = "default_external_policy_timeout_ms",
+        deserialize_with = "duration::deserialize_ms"
+    )]
+    external_policy_timeout_ms: u64,
+    /// additionally limit the traffic once this many bytes were sent since local midnight,
+    /// independent of the window
+    #[serde(default)]
+    daily_max_bytes: Option<u64>,
+    /// traffic during these hours isn't counted towards `max_bytes`
+    #[serde(default)]
+    off_peak: Option<OffPeak>,
+    /// overrides applied while the default route goes through the given connection type,
+    /// which is one of `ethernet`, `wifi` or `lte`
+    #[serde(default)]
+    connection_profiles: BTreeMap<String, ConnectionProfile>,
+    /// calibration factor applied to the measured bytes before comparing them to `max_bytes`,
+    /// replaced by the factor derived from the `calibrate` command once it was used.
+    /// `tx_bytes` includes link-layer framing which ISPs metering at the IP layer don't bill,
+    /// so e.g. `0.97` makes our count match such an ISP more closely
+    #[serde(default = "default_overhead_correction")]
+    overhead_correction: f64,
+    /// advanced: rounds the (corrected) usage up to a multiple of this many bytes to model ISPs
+    /// billing in fixed increments, 0 disables rounding
+    #[serde(default)]
+    accounting_quantum_bytes: u64,
+    /// bytes already used in the current window before the tool got installed, e.g. as reported
+    /// by the ISP, only applied when no state exists yet
+    #[serde(default)]
+    initial_usage_bytes: u64,
+    /// bytes the usage may exceed `max_bytes` by before the limitation gets applied, see
+    /// [`Meta::absorb_burst`], 0 disables the allowance
+    #[serde(default)]
+    burst_allowance_bytes: u64,
+    /// time under the cap in which a used up burst allowance replenishes completely, it doesn't
+    /// replenish while over the cap
+    #[serde(
+        default = "default_burst_refill_ms",
+        deserialize_with = "duration::deserialize_ms"
+    )]
+    burst_refill_ms: u64,
+    /// the only interface counted and limited if it exists, see [`wan`]
+    #[serde(default)]
+    wan_interface: Option<String>,
+    /// discover `wan_interface` if it's unset or disappeared, enabled for new configs
+    #[serde(default)]
+    auto_discover_wan: bool,
+    /// glob patterns of the interfaces to count, all interfaces are counted if this is empty
+    #[serde(default)]
+    include_interfaces: Vec<String>,
+    /// glob patterns of interfaces to leave out even if they are included
+    #[serde(default)]
+    exclude_interfaces: Vec<String>,
+    /// multiplier of the traffic of an interface, e.g. for a backup link billed at a different
+    /// rate. Interfaces not listed count with 1.0, a changed weight applies to the traffic from
+    /// then on
+    #[serde(default)]
+    interface_weights: BTreeMap<String, f64>,
+    /// glob patterns narrowing down the counted interfaces to those carrying the upload,
+    /// for setups where the upload and download go over different interfaces
+    #[serde(default)]
+    outbound_interfaces: Vec<String>,
+    /// glob patterns of the interfaces whose received bytes are counted too,
+    /// received traffic isn't counted if this is empty
+    #[serde(default)]
+    inbound_interfaces: Vec<String>,
+    /// only count the traffic sent to the peers of this WireGuard interface instead, see [`wireguard`]
+    #[serde(default)]
+    wireguard_interface: Option<String>,
+    /// public keys of the peers to count, all peers are counted if this is empty
+    #[serde(default)]
+    wireguard_peers: Vec<String>,
+    /// whether loopback and other virtual interfaces are counted, see [`interfaces::is_virtual`]
+    #[serde(default)]
+    count_virtual_interfaces: bool,
+    /// only count the interfaces carrying the default route
+    #[serde(default)]
+    track_default_route_only: bool,
+    #[serde(default)]
+    multi_default_route: MultiDefaultRoute,
+    /// every this many checks the applied qdiscs get compared to the throttle state so that
+    /// manual `tc` changes get corrected, 0 disables this
+    #[serde(default = "default_reconcile_every_n_checks")]
+    reconcile_every_n_checks: u64,
+    /// what happens to the interfaces matching these glob patterns while over the cap,
+    /// interfaces matching none of them get shaped
+    #[serde(default)]
+    interface_policies: BTreeMap<String, InterfacePolicy>,
+    /// how the limit is distributed over the interfaces
+    #[serde(default)]
+    throttle_split: ThrottleSplit,
+    /// while throttled until the window resets the daemon wakes up at least this often and logs
+    /// its progress, 0 disables the progress logs
+    #[serde(
+        default = "default_max_sleep_chunk_ms",
+        deserialize_with = "duration::deserialize_ms"
+    )]
+    max_sleep_chunk_ms: u64,
+    /// on `SIGTERM` or `SIGINT` wait up to this long for running limitation changes to finish
+    /// and flush the state before exiting, without this the process gets killed right away
+    #[serde(default)]
+    drain_timeout_ms: Option<u64>,
+    /// measure the rate achieved after applying the limitation and alert if it's well above
+    /// the limit
+    #[serde(default)]
+    verify_throttle: bool,
+    /// pause counting and enforcement while a package manager is running, see [`updates`] and
+    /// [`Pause`]
+    #[serde(default)]
+    pause_during_updates: bool,
+    /// how traffic gets discouraged while over the cap
+    #[serde(default)]
+    enforcement: Enforcement,
+    /// delay added to every packet with `"enforcement": "netem"`
+    #[serde(default = "default_netem_delay_ms")]
+    netem_delay_ms: u64,
+    /// percentage of packets dropped with `"enforcement": "netem"`
+    #[serde(default)]
+    netem_loss_percent: f64,
+    /// alerts fired as the usage approaches the cap, see [`alerts`]
+    #[serde(default = "alerts::default_tiers")]
+    alert_tiers: Vec<AlertTier>,
+    /// report a spike once the send rate exceeds the trailing average this many times,
+    /// none disables spike detection
+    #[serde(default)]
+    spike_multiplier: Option<f64>,
+    /// timeframe the trailing average used for spike detection is computed over
+    #[serde(
+        default = "default_spike_baseline_window_ms",
+        deserialize_with = "duration::deserialize_ms"
+    )]
+    spike_baseline_window_ms: u64,
+    /// shell commands run whenever a spike is detected or the limitation turns out to be
+    /// ineffective, see `verify_throttle`
+    #[serde(default)]
+    anomaly_commands: Vec<String>,
+    /// run `modprobe` for the qdisc modules if the kernel lacks them, without them `htb` stands in
+    /// for `tbf` and if that's missing as well the usage only gets observed for an hour before
+    /// trying again
+    #[serde(default)]
+    auto_load_modules: bool,
+    /// shell commands run once before the limitation gets applied for the first time,
+    /// e.g. to load kernel modules or create `ifb` devices
+    #[serde(default)]
+    pre_throttle_commands: Vec<String>,
+    /// shell commands run after every removal of the limitation
+    #[serde(default)]
+    post_cleanup_commands: Vec<String>,
+    /// encrypt the state file, see [`crypto`] for how the key is provided
+    #[serde(default)]
+    encrypt_state: bool,
+    /// see [`credential_path`]
+    #[serde(default)]
+    state_key_file: Option<PathBuf>,
+    /// don't persist anything, see [`ephemeral`]
+    #[serde(default)]
+    ephemeral: bool,
+    /// prefixed to every log line and included in events, defaults to the hostname
+    #[serde(default)]
+    instance_label: Option<String>,
+    #[serde(default)]
+    log_format: LogFormat,
+}
+
+fn default_reconcile_every_n_checks() -> u64 {
+    30
+}
+
+fn default_max_sleep_chunk_ms() -> u64 {
+    1000 * 60 * 60
+}
+
+fn default_burst_refill_ms() -> u64 {
+    1000 * 60 * 60
+}
+
+fn default_spike_baseline_window_ms() -> u64 {
+    1000 * 60 * 60
+}
+
+fn default_netem_delay_ms() -> u64 {
+    200
+}
+
+fn default_overhead_correction() -> f64 {
+    1.0
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct ConnectionProfile {
+    max_bytes: Option<u64>,
+    lower_limit_bytes: Option<u64>,
+    burst_buffer_size: Option<u64>,
+    buffer_latency_ms: Option<u64>,
+}
+
+/// Daily timeframe in local time, `end_hour` may be smaller than `start_hour` to wrap around midnight.
+#[derive(Serialize, Deserialize, Clone)]
+struct OffPeak {
+    start_hour: u32,
+    end_hour: u32,
+}
+
+impl OffPeak {
+    fn contains(&self, hour: u32) -> bool {
+        if self.start_hour <= self.end_hour {
+            hour >= self.start_hour && hour < self.end_hour
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+fn default_external_policy_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_auto_rate_fallback_bytes() -> u64 {
+    64 * 1024
+}
+
+fn default_panic_cooldown_ms() -> u64 {
+    1000 * 60 * 15
 }
 
 impl Config {
+    /// Returns the config with the profile of `connection` applied and an automatic
+    /// `lower_limit_bytes` resolved.
+    fn for_connection(&self, connection: Option<&str>) -> Config {
+        let mut config = self.clone();
+        if let Some(profile) =
+            connection.and_then(|connection| self.connection_profiles.get(connection))
+        {
+            config.max_bytes = profile.max_bytes.or(config.max_bytes);
+            config.lower_limit_bytes = profile
+                .lower_limit_bytes
+                .unwrap_or(config.lower_limit_bytes);
+            config.burst_buffer_size = profile
+                .burst_buffer_size
+                .unwrap_or(config.burst_buffer_size);
+            config.buffer_latency_ms = profile
+                .buffer_latency_ms
+                .unwrap_or(config.buffer_latency_ms);
+        }
+        if config.lower_limit_bytes == 0 {
+            config.lower_limit_bytes = shaping::auto_rate(&config);
+        }
+        config
+    }
+
+    fn enforcing_at(&self, now_ms: u64) -> bool {
+        self.enforcement_start_at_ms
+            .is_none_or(|start| now_ms >= start)
+    }
+
+    /// Reads the whole config from stdin, every following [`Config::load`] returns it instead
+    /// of reading or creating the config file. The state is still stored in the config directory.
+    fn read_stdin() {
+        let mut raw = vec![];
+        let config = io::stdin()
+            .read_to_end(&mut raw)
+            .map_err(|err| err.to_string())
+            .and_then(|_| serde_json::from_slice::<Config>(&raw).map_err(|err| err.to_string()));
+        match config {
+            Ok(config) => {
+                let config = config.resolve_credentials();
+                if !ephemeral() {
+                    fs::create_dir_all(config_path("config.json").parent().unwrap()).unwrap();
+                }
+                let _ = STDIN_CONFIG.set(config);
+            }
+            Err(err) => {
+                eprintln!("Couldn't read the config from stdin: {err}");
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Config written on first run.
+    fn defaults() -> Self {
+        Config {
+            save_interval_ms: 1000 * 60,
+            check_interval_ms: 1000 * 10,
+            save_every_n_bytes: 1024 * 1024 * 64,
+            min_store_interval_ms: 0,
+            capture_timeframe_ms: 1000 * 60 * 60 * 24 * 7,
+            max_bytes: Some(1024 * 1024 * 1024 * 1024),
+            lower_limit_bytes: 0,
+            burst_buffer_size: 4096,
+            buffer_latency_ms: 50,
+            auto_rate_fallback_bytes: default_auto_rate_fallback_bytes(),
+            startup_fallback_rate_bytes: None,
+            throttle_dscp_match: vec![],
+            panic_cooldown_ms: default_panic_cooldown_ms(),
+            enforcement_start_at_ms: None,
+            external_policy_socket: None,
+            external_policy_timeout_ms: default_external_policy_timeout_ms(),
+            daily_max_bytes: None,
+            off_peak: None,
+            connection_profiles: BTreeMap::new(),
+            overhead_correction: default_overhead_correction(),
+            accounting_quantum_bytes: 0,
+            initial_usage_bytes: 0,
+            burst_allowance_bytes: 0,
+            burst_refill_ms: default_burst_refill_ms(),
+            wan_interface: wan::discover(),
+            auto_discover_wan: true,
+            include_interfaces: vec![],
+            exclude_interfaces: vec![],
+            interface_weights: BTreeMap::new(),
+            outbound_interfaces: vec![],
+            inbound_interfaces: vec![],
+            wireguard_interface: None,
+            wireguard_peers: vec![],
+            count_virtual_interfaces: false,
+            track_default_route_only: false,
+            multi_default_route: MultiDefaultRoute::default(),
+            reconcile_every_n_checks: default_reconcile_every_n_checks(),
+            interface_policies: BTreeMap::new(),
+            throttle_split: ThrottleSplit::default(),
+            max_sleep_chunk_ms: default_max_sleep_chunk_ms(),
+            drain_timeout_ms: None,
+            verify_throttle: false,
+            pause_during_updates: false,
+            enforcement: Enforcement::default(),
+            netem_delay_ms: default_netem_delay_ms(),
+            netem_loss_percent: 0.0,
+            alert_tiers: alerts::default_tiers(),
+            spike_multiplier: None,
+            spike_baseline_window_ms: default_spike_baseline_window_ms(),
+            anomaly_commands: vec![],
+            auto_load_modules: false,
+            pre_throttle_commands: vec![],
+            post_cleanup_commands: vec![],
+            encrypt_state: false,
+            state_key_file: None,
+            max_bytes_file: None,
+            ephemeral: false,
+            instance_label: None,
+            log_format: LogFormat::default(),
+        }
+    }
+
+    fn write(&self, path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, serde_json::to_string_pretty(self).unwrap()).unwrap();
+    }
+
     fn load() -> Self {
-        let cfg_path = dirs::config_dir()
-            .map(|mut dir| {
-                dir.push("traffic_tracker");
-                dir.push("config.json");
-                dir
-            })
-            .unwrap_or_else(|| PathBuf::from_str("./traffic_tracker/config.json").unwrap());
+        if let Some(config) = STDIN_CONFIG.get() {
+            return config.clone();
+        }
+        let cfg_path = config_path("config.json");
         if !cfg_path.exists() {
-            let cfg = Config {
-                save_interval_ms: 1000 * 60,
-                check_interval_ms: 1000 * 10,
-                save_every_n_bytes: 1024 * 1024 * 64,
-                capture_timeframe_ms: 1000 * 60 * 60 * 24 * 7,
-                max_bytes: 1024 * 1024 * 1024 * 1024,
-                lower_limit_bytes: 64 * 1024,
-                burst_buffer_size: 4096,
-                buffer_latency_ms: 50,
-            };
-            fs::write(cfg_path, serde_json::to_string_pretty(&cfg).unwrap()).unwrap();
+            if NO_DEFAULT_CONFIG.load(Ordering::SeqCst) {
+                eprintln!(
+                    "No config found at {}, run `init` to create one",
+                    cfg_path.display()
+                );
+                process::exit(1);
+            }
+            let cfg = Config::defaults();
+            if !ephemeral() {
+                cfg.write(&cfg_path);
+                log(&format!(
+                    "!!! No config found, created a default config at {} limiting to {} bytes per window, review it !!!",
+                    cfg_path.display(),
+                    cfg.max_bytes()
+                ));
+            }
             return cfg;
         }
-        serde_json::from_slice(&fs::read(cfg_path).unwrap()).unwrap()
+        serde_json::from_slice::<Config>(&fs::read(cfg_path).unwrap())
+            .unwrap()
+            .resolve_credentials()
+    }
+
+    /// Cap per window, always set once the config got loaded, see [`Config::resolve_credentials`].
+    fn max_bytes(&self) -> u64 {
+        self.max_bytes.unwrap_or_default()
+    }
+
+    /// Replaces values with the contents of the files configured for them.
+    fn resolve_credentials(mut self) -> Self {
+        if self.max_bytes.is_none() && self.max_bytes_file.is_none() {
+            eprintln!("The config sets neither max_bytes nor max_bytes_file");
+            process::exit(1);
+        }
+        if self.external_policy_timeout_ms == 0 {
+            // every check would wait on an unresponsive policy forever
+            eprintln!("The config sets external_policy_timeout_ms to 0, it has to be positive");
+            process::exit(1);
+        }
+        if let Some(file) = &self.max_bytes_file {
+            let path = credential_path(file);
+            let max_bytes = fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|raw| raw.trim().parse::<u64>().map_err(|err| err.to_string()));
+            match max_bytes {
+                Ok(max_bytes) => self.max_bytes = Some(max_bytes),
+                Err(err) => {
+                    eprintln!("Couldn't read max_bytes from {}: {err}", path.display());
+                    process::exit(1);
+                }
+            }
+        }
+        self.state_key_file = self.state_key_file.as_deref().map(credential_path);
+        self
+    }
+}
+
+/// Relative paths of secret files are looked up among the systemd credentials
+/// (`LoadCredential=`) if the daemon runs with any, otherwise they're relative to the working
+/// directory.
+fn credential_path(path: &Path) -> PathBuf {
+    match env::var_os("CREDENTIALS_DIRECTORY") {
+        Some(dir) if path.is_relative() => Path::new(&dir).join(path),
+        _ => path.to_path_buf(),
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Meta {
     reset_at_ms: u64,
     starting_bytes: String,
     last_saved_bytes: String,
+    /// whether the restriction is currently applied
+    #[serde(default)]
+    throttled: bool,
+    /// set by the `pause` command, panic resets and `pause_during_updates`
+    #[serde(default)]
+    pause: Option<Pause>,
+    /// end of an enforcement pause in states written before [`Pause`] existed
+    #[serde(default, skip_serializing)]
+    enforcement_paused_until_ms: u64,
+    /// extra quota granted by the `boost` command
+    #[serde(default)]
+    boost: Option<Boost>,
+    /// bytes used in the current window which couldn't be counted, set from
+    /// `initial_usage_bytes` and by the `set-usage` command
+    #[serde(default)]
+    usage_offset_bytes: u64,
+    /// bytes left in the burst allowance, negative once the excess used it up, see
+    /// [`Meta::absorb_burst`]
+    #[serde(default)]
+    burst_credit_bytes: f64,
+    /// bytes by which the usage exceeded the cap at the previous check
+    #[serde(default)]
+    burst_excess_bytes: f64,
+    #[serde(default)]
+    burst_refilled_at_ms: u64,
+    /// names of the alert tiers which fired in the current window
+    #[serde(default)]
+    fired_alert_tiers: Vec<String>,
+    #[serde(default)]
+    ledger: Ledger,
+    /// the usage is the difference between this counter and `starting_bytes`
+    #[serde(default)]
+    counter: Counter,
+    /// bytes sent during off-peak hours in the current window
+    #[serde(default = "zero_bytes")]
+    off_peak_bytes: String,
+    /// bytes sent since local midnight, off-peak traffic is excluded
+    #[serde(default = "zero_bytes")]
+    daily_bytes: String,
+    /// bytes sent during every hour of the day (local time) in the current window, off-peak
+    /// traffic is excluded
+    #[serde(default)]
+    hourly_bytes: Vec<u64>,
+    /// bytes received on the inbound interfaces and sent on the counted interfaces in the
+    /// current window, including off-peak traffic
+    #[serde(default)]
+    inbound_bytes: u64,
+    #[serde(default)]
+    outbound_bytes: u64,
+    /// bytes per counter in the current window, including off-peak traffic, received bytes are
+    /// keyed by `<interface>:rx`
+    #[serde(default)]
+    interface_bytes: BTreeMap<String, u64>,
+    /// next local midnight at which [`Meta::daily_bytes`] starts over
+    #[serde(default)]
+    daily_reset_at_ms: u64,
+    /// exponentially smoothed send rate in bytes per second
+    #[serde(skip)]
+    smoothed_rate: f64,
+    /// type of the active connection if connection profiles are configured
+    #[serde(skip)]
+    connection: Option<&'static str>,
+    #[serde(skip)]
+    timers: Timers,
+    /// time spent throttled within the current window
+    #[serde(default)]
+    throttled_ms: u64,
+    /// up to when the throttled time got accounted into [`Meta::throttled_ms`]
+    #[serde(skip)]
+    throttle_accounted_ms: u64,
+    /// rate of the currently applied limitation
+    #[serde(skip)]
+    applied_rate: u64,
+    /// smoothed send rate per interface in bytes per second
+    #[serde(skip)]
+    interface_rates: BTreeMap<String, f64>,
+    /// usage shares the currently applied limitation was split by
+    #[serde(skip)]
+    applied_shares: BTreeMap<String, f64>,
+    /// see [`calibration::factor`]
+    #[serde(skip)]
+    calibrated_correction: Option<f64>,
+}
+
+/// Last and next run of each thread, for diagnosing scheduling issues.
+/// The resetter's next run is [`Meta::reset_at_ms`].
+#[derive(Clone, Default)]
+struct Timers {
+    save_last_ms: u64,
+    save_next_ms: u64,
+    reset_last_ms: u64,
+    check_last_ms: u64,
+    check_next_ms: u64,
+}
+
+impl Timers {
+    fn report(&self, reset_at_ms: u64) -> String {
+        let format = |ms: u64| {
+            if ms == 0 {
+                "never".to_string()
+            } else {
+                format_millis(ms)
+            }
+        };
+        format!(
+            "save thread:    last {}, next {}\nreset thread:   last {}, next {}\ncheck loop:     last {}, next {}",
+            format(self.save_last_ms),
+            format(self.save_next_ms),
+            format(self.reset_last_ms),
+            format(reset_at_ms),
+            format(self.check_last_ms),
+            format(self.check_next_ms),
+        )
+    }
+}
+
+/// see [`Meta::store`], the lock also keeps the threads from writing the file concurrently
+static HIGHEST_SAVED_BYTES: Mutex<Option<BigUint>> = Mutex::new(None);
+
+/// `min_store_interval_ms` of the config, set by [`Meta::load`]
+static MIN_STORE_INTERVAL_MS: AtomicU64 = AtomicU64::new(0);
+/// when the state got written last
+static LAST_STORE_MS: AtomicU64 = AtomicU64::new(0);
+
+/// weight of the newest sample in [`Meta::smoothed_rate`]
+const RATE_SMOOTHING: f64 = 0.2;
+/// reset point of a fresh state, already elapsed so that the first real window starts right away
+const PLACEHOLDER_RESET_AT_MS: u64 = Duration::from_days(7).as_millis() as u64;
+
+/// First local midnight after `after_ms`.
+fn next_local_midnight_ms(after_ms: u64) -> u64 {
+    let tomorrow = chrono::DateTime::from_timestamp_millis(after_ms as i64)
+        .unwrap()
+        .with_timezone(&Local)
+        .date_naive()
+        .succ_opt()
+        .unwrap();
+    tomorrow
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|midnight| midnight.timestamp_millis() as u64)
+        // midnight doesn't exist on this day due to a DST change
+        .unwrap_or(after_ms + Duration::from_hours(24).as_millis() as u64)
+}
+
+fn zero_bytes() -> String {
+    "0".to_string()
 }
 
 impl Meta {
     fn path() -> PathBuf {
-        dirs::config_dir()
-            .map(|mut dir| {
-                dir.push("traffic_tracker");
-                dir.push("meta.json");
-                dir
-            })
-            .unwrap_or_else(|| PathBuf::from_str("./traffic_tracker/meta.json").unwrap())
+        config_path("meta.json")
     }
 
-    fn load() -> Self {
+    /// State of a window starting at the counter reading `sent_bytes`.
+    fn fresh(sent_bytes: String, config: &Config) -> Self {
+        Meta {
+            reset_at_ms: PLACEHOLDER_RESET_AT_MS,
+            last_saved_bytes: sent_bytes.clone(),
+            starting_bytes: sent_bytes.clone(),
+            throttled: false,
+            pause: None,
+            enforcement_paused_until_ms: 0,
+            boost: None,
+            usage_offset_bytes: config.initial_usage_bytes,
+            burst_credit_bytes: 0.0,
+            burst_excess_bytes: 0.0,
+            burst_refilled_at_ms: 0,
+            fired_alert_tiers: vec![],
+            ledger: Ledger::default(),
+            counter: Counter::starting_at(&sent_bytes),
+            off_peak_bytes: zero_bytes(),
+            daily_bytes: zero_bytes(),
+            daily_reset_at_ms: 0,
+            hourly_bytes: vec![],
+            inbound_bytes: 0,
+            outbound_bytes: 0,
+            interface_bytes: BTreeMap::new(),
+            smoothed_rate: 0.0,
+            connection: None,
+            timers: Timers::default(),
+            throttled_ms: 0,
+            throttle_accounted_ms: 0,
+            applied_rate: 0,
+            interface_rates: BTreeMap::new(),
+            applied_shares: BTreeMap::new(),
+            calibrated_correction: None,
+        }
+    }
+
+    fn load(config: &Config) -> Self {
+        MIN_STORE_INTERVAL_MS.store(config.min_store_interval_ms, Ordering::SeqCst);
         let cfg_path = Self::path();
-        if !cfg_path.exists() {
-            let sent_bytes = fetch_outbound_bytes().to_string();
-            let cfg = Meta {
-                reset_at_ms: Duration::from_days(7).as_millis() as u64,
-                last_saved_bytes: sent_bytes.clone(),
-                starting_bytes: sent_bytes,
-            };
-            fs::create_dir_all(cfg_path.parent().unwrap()).unwrap();
-            fs::write(cfg_path, serde_json::to_string_pretty(&cfg).unwrap()).unwrap();
+        if ephemeral() || !cfg_path.exists() {
+            let cfg = Meta::fresh(zero_bytes(), config);
+            if !ephemeral() {
+                fs::create_dir_all(cfg_path.parent().unwrap()).unwrap();
+                cfg.store();
+            }
             return cfg;
         }
-        serde_json::from_slice(&fs::read(cfg_path).unwrap()).unwrap()
+        match crypto::open(fs::read(cfg_path).unwrap()) {
+            Ok(raw) => {
+                let mut meta = serde_json::from_slice::<Meta>(&raw).unwrap();
+                if meta.counter.bytes.is_empty() {
+                    // `starting_bytes` used to refer to the sum of the interface counters, which
+                    // `last_saved_bytes` still holds
+                    meta.counter = Counter::starting_at(&meta.last_saved_bytes);
+                }
+                if meta.enforcement_paused_until_ms != 0 && meta.pause.is_none() {
+                    meta.pause = Some(Pause {
+                        started_at_ms: current_time_millis() as u64,
+                        until_ms: Some(meta.enforcement_paused_until_ms),
+                        gates_baseline: false,
+                    });
+                }
+                meta
+            }
+            Err(err) => {
+                log(&err);
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Reads the interface counters and advances [`Meta::counter`], returns its new value. Every
+    /// thread reads through here, so a spurious zero doesn't become the baseline of later reads.
+    fn sample(&mut self, config: &Config) -> BigUint {
+        let counters = fetch_interface_bytes_checked(config, &self.ledger.last_seen);
+        self.advance(&counters, config)
+    }
+
+    fn advance(&mut self, counters: &[(String, u64)], config: &Config) -> BigUint {
+        self.ledger.record(counters);
+        self.counter
+            .advance(counters, |counter| weight(config, counter))
+    }
+
+    /// Starts counting the daily usage anew once local midnight passed, returns whether it did.
+    fn roll_over_day(&mut self, now_ms: u64) -> bool {
+        let rolled_over = now_ms >= self.daily_reset_at_ms;
+        if rolled_over {
+            self.daily_bytes = zero_bytes();
+            self.daily_reset_at_ms = next_local_midnight_ms(now_ms);
+        }
+        rolled_over
+    }
+
+    /// Books `delta` bytes sent at `now_ms` during the local `hour` as off-peak or daily usage,
+    /// traffic sent while counting is paused doesn't count at all.
+    fn account(&mut self, delta: &BigUint, now_ms: u64, hour: u32, config: &Config) {
+        if self.counting_paused(now_ms) {
+            // advancing the baseline keeps the update traffic out of the window
+            let starting = BigUint::from_str(&self.starting_bytes).unwrap() + delta;
+            self.starting_bytes = starting.to_string();
+        } else if config
+            .off_peak
+            .as_ref()
+            .is_some_and(|off_peak| off_peak.contains(hour))
+        {
+            let off_peak = BigUint::from_str(&self.off_peak_bytes).unwrap() + delta;
+            self.off_peak_bytes = off_peak.to_string();
+        } else {
+            let daily = BigUint::from_str(&self.daily_bytes).unwrap() + delta;
+            self.daily_bytes = daily.to_string();
+            self.hourly_bytes.resize(24, 0);
+            let hour = hour as usize;
+            self.hourly_bytes[hour] =
+                self.hourly_bytes[hour].saturating_add(u64::try_from(delta).unwrap_or(u64::MAX));
+        }
+    }
+
+    /// Drops overrides which expired while the daemon wasn't running and reports the ones
+    /// still active.
+    fn restore_overrides(&mut self) {
+        let now = current_time_millis() as u64;
+        match &self.boost {
+            Some(boost) if boost.expires_at_ms <= now => {
+                log("Dropped the boost which expired while not running");
+                self.boost = None;
+                self.store();
+            }
+            Some(boost) => log(&format!(
+                "Restored boost of {} bytes until {}",
+                boost.bytes,
+                format_millis(boost.expires_at_ms)
+            )),
+            None => {}
+        }
+        match &self.pause {
+            Some(pause) if !pause.active(now) => {
+                log("Dropped the pause which ended while not running");
+                self.pause = None;
+                self.store();
+            }
+            Some(pause) => log(&format!(
+                "{} since {}",
+                pause.describe(),
+                format_millis(pause.started_at_ms)
+            )),
+            None => {}
+        }
     }
 
+    /// Bytes sent in the current window excluding off-peak traffic, before any correction.
+    fn raw_used_bytes(&self, curr_bytes: &BigUint) -> u64 {
+        let starting = BigUint::from_str(&self.starting_bytes).unwrap();
+        let off_peak = BigUint::from_str(&self.off_peak_bytes).unwrap();
+        let used = if *curr_bytes > &starting + &off_peak {
+            curr_bytes - starting - &off_peak
+        } else {
+            BigUint::ZERO
+        };
+        u64::try_from(used).unwrap_or(u64::MAX)
+    }
+
+    /// Takes up to `bytes` out of the usage, first out of the usage which couldn't be counted,
+    /// and returns how much got refunded. The usage can't go below zero.
+    fn refund(&mut self, bytes: u64, curr_bytes: &BigUint) -> u64 {
+        let offset = bytes.min(self.usage_offset_bytes);
+        self.usage_offset_bytes -= offset;
+        let counted = (bytes - offset).min(self.raw_used_bytes(curr_bytes));
+        let starting = BigUint::from_str(&self.starting_bytes).unwrap() + counted;
+        self.starting_bytes = starting.to_string();
+        offset + counted
+    }
+
+    /// Factor applied to the counted bytes, calibrated against the ISP if possible.
+    fn correction(&self, config: &Config) -> f64 {
+        self.calibrated_correction
+            .unwrap_or(config.overhead_correction)
+    }
+
+    /// Whether a pause suspends the enforcement at `now_ms`.
+    fn paused(&self, now_ms: u64) -> bool {
+        self.pause
+            .as_ref()
+            .is_some_and(|pause| pause.active(now_ms))
+    }
+
+    /// Whether the traffic sent at `now_ms` is kept out of the window.
+    fn counting_paused(&self, now_ms: u64) -> bool {
+        self.pause
+            .as_ref()
+            .is_some_and(|pause| pause.gates_baseline && pause.active(now_ms))
+    }
+
+    /// Extra quota of the boost if it's still active.
+    fn boost_bytes(&self, now_ms: u64) -> u64 {
+        self.boost
+            .as_ref()
+            .filter(|boost| boost.expires_at_ms > now_ms)
+            .map_or(0, |boost| boost.bytes)
+    }
+
+    /// Token bucket holding `burst_allowance_bytes` which refills over `burst_refill_ms`, the
+    /// traffic exceeding the cap is taken out of it. Returns whether the bucket still covers the
+    /// excess, short bursts thus pass while sustained traffic over the cap gets limited.
+    ///
+    /// The bucket only refills while the usage is under the cap, e.g. after a boost or a refund,
+    /// so sustained traffic can't exceed the cap by more than the allowance.
+    fn absorb_burst(&mut self, usage: &Usage, now_ms: u64, config: &Config) -> bool {
+        let excess = usage.used_bytes.saturating_sub(usage.max_bytes) as f64;
+        let capacity = config.burst_allowance_bytes as f64;
+        if excess == 0.0 {
+            let elapsed_ms = now_ms.saturating_sub(self.burst_refilled_at_ms) as f64;
+            let refill = capacity * elapsed_ms / config.burst_refill_ms.max(1) as f64;
+            self.burst_credit_bytes = (self.burst_credit_bytes.max(0.0) + refill).min(capacity);
+        } else {
+            // only the bytes which went over the cap since the previous check are taken out
+            self.burst_credit_bytes -= (excess - self.burst_excess_bytes).max(0.0);
+        }
+        self.burst_excess_bytes = excess;
+        self.burst_refilled_at_ms = now_ms;
+        self.burst_credit_bytes >= 0.0
+    }
+
+    /// Adds the time spent throttled since the last call to [`Meta::throttled_ms`].
+    fn account_throttled(&mut self) {
+        let now = current_time_millis() as u64;
+        if self.throttled && self.throttle_accounted_ms != 0 {
+            self.throttled_ms += now.saturating_sub(self.throttle_accounted_ms);
+        }
+        self.throttle_accounted_ms = now;
+    }
+
+    fn set_throttled(&mut self, throttled: bool) {
+        self.account_throttled();
+        if self.throttled != throttled {
+            events::publish(Event::Throttle {
+                throttled,
+                rate: self.applied_rate,
+            });
+        }
+        self.throttled = throttled;
+    }
+
+    /// Every write of the state goes through here. The threads take their counter readings at
+    /// slightly different times, so the highest `last_saved_bytes` stored by this process gets
+    /// persisted to keep the stored value from going backwards.
+    ///
+    /// Writes within `min_store_interval_ms` of the previous one get dropped, the state is then
+    /// only persisted by a later write and is lost if the host crashes in between.
     fn store(&self) {
-        let cfg_path = Self::path();
-        fs::write(cfg_path, serde_json::to_string_pretty(self).unwrap()).unwrap();
+        self.write(false);
+    }
+
+    /// Like [`Meta::store`] but ignores `min_store_interval_ms`, for the final write before exiting.
+    fn flush(&self) {
+        self.write(true);
+    }
+
+    fn write(&self, force: bool) {
+        if ephemeral() {
+            return;
+        }
+        let mut highest = HIGHEST_SAVED_BYTES.lock().unwrap();
+        let saved = BigUint::from_str(&self.last_saved_bytes).unwrap();
+        if highest.as_ref().is_none_or(|highest| saved > *highest) {
+            *highest = Some(saved);
+        }
+        let now = current_time_millis() as u64;
+        let last_ms = LAST_STORE_MS.load(Ordering::SeqCst);
+        if !force && last_ms != 0 && now < last_ms + MIN_STORE_INTERVAL_MS.load(Ordering::SeqCst) {
+            return;
+        }
+        let meta = Meta {
+            last_saved_bytes: highest.as_ref().unwrap().to_string(),
+            ..self.clone()
+        };
+        let raw = serde_json::to_string_pretty(&meta).unwrap().into_bytes();
+        fs::write(Self::path(), crypto::seal(raw)).unwrap();
+        LAST_STORE_MS.store(now, Ordering::SeqCst);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Boost {
+    bytes: u64,
+    expires_at_ms: u64,
+}
+
+/// No limitation gets applied during a pause and, if it gates the baseline, the traffic sent
+/// during it isn't counted either.
+#[derive(Serialize, Deserialize, Clone)]
+struct Pause {
+    started_at_ms: u64,
+    /// `None` for a pause lasting until the package manager updates finished
+    until_ms: Option<u64>,
+    /// whether the baseline advances with the traffic so that it stays out of the window
+    gates_baseline: bool,
+}
+
+impl Pause {
+    fn active(&self, now_ms: u64) -> bool {
+        self.until_ms.is_none_or(|until_ms| now_ms < until_ms)
+    }
+
+    fn describe(&self) -> String {
+        let what = if self.gates_baseline {
+            "Counting and enforcement"
+        } else {
+            "Enforcement"
+        };
+        match self.until_ms {
+            Some(until_ms) => format!("{what} paused until {}", format_millis(until_ms)),
+            None => format!("{what} paused until the updates finished"),
+        }
+    }
+}
+
+/// Snapshot of the usage within the current window.
+#[derive(Serialize)]
+struct Usage {
+    /// bytes counted towards `max_bytes`, off-peak traffic is excluded
+    used_bytes: u64,
+    off_peak_bytes: u64,
+    max_bytes: u64,
+    remaining_bytes: u64,
+    inbound_bytes: u64,
+    outbound_bytes: u64,
+    /// factor applied to the measured bytes, see `overhead_correction` and [`calibration`]
+    correction_factor: f64,
+    /// bytes counted towards `daily_max_bytes` since local midnight
+    daily_used_bytes: u64,
+    daily_max_bytes: Option<u64>,
+    reset_at_ms: u64,
+    throttled: bool,
+    /// time spent throttled within the current window
+    throttled_ms: u64,
+    smoothed_rate_bytes_per_sec: f64,
+    /// plain-language projection of the usage until the window resets
+    summary: String,
+    /// all bytes sent since tracking started, across reboots
+    lifetime_bytes: u64,
+    /// only filled in for the `status` command since it queries `tc`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qdiscs: Option<Vec<shaping::QdiscStatus>>,
+}
+
+impl Usage {
+    fn compute(meta: &Meta, curr_bytes: &BigUint, config: &Config) -> Self {
+        // an active boost raises the cap
+        let max_bytes = config
+            .max_bytes()
+            .saturating_add(meta.boost_bytes(current_time_millis() as u64));
+        let off_peak = BigUint::from_str(&meta.off_peak_bytes).unwrap();
+        let correction = meta.correction(config);
+        let used_bytes = ((meta.raw_used_bytes(curr_bytes) as f64 * correction) as u64)
+            .saturating_add(meta.usage_offset_bytes);
+        let used_bytes = if config.accounting_quantum_bytes == 0 {
+            used_bytes
+        } else {
+            used_bytes
+                .checked_next_multiple_of(config.accounting_quantum_bytes)
+                .unwrap_or(u64::MAX)
+        };
+        let remaining_bytes = max_bytes.saturating_sub(used_bytes);
+        Usage {
+            used_bytes,
+            off_peak_bytes: u64::try_from(off_peak).unwrap_or(u64::MAX),
+            max_bytes,
+            remaining_bytes,
+            inbound_bytes: meta.inbound_bytes,
+            outbound_bytes: meta.outbound_bytes,
+            correction_factor: correction,
+            daily_used_bytes: (u64::try_from(BigUint::from_str(&meta.daily_bytes).unwrap())
+                .unwrap_or(u64::MAX) as f64
+                * correction) as u64,
+            daily_max_bytes: config.daily_max_bytes,
+            reset_at_ms: meta.reset_at_ms,
+            throttled: meta.throttled,
+            throttled_ms: meta.throttled_ms,
+            smoothed_rate_bytes_per_sec: meta.smoothed_rate,
+            summary: summarize(remaining_bytes, meta.smoothed_rate, meta.reset_at_ms),
+            lifetime_bytes: u64::try_from(meta.ledger.lifetime()).unwrap_or(u64::MAX),
+            qdiscs: None,
+        }
+    }
+}
+
+fn summarize(remaining_bytes: u64, rate: f64, reset_at_ms: u64) -> String {
+    const DAY_MS: f64 = 1000.0 * 60.0 * 60.0 * 24.0;
+    let reset_days = reset_at_ms.saturating_sub(current_time_millis() as u64) as f64 / DAY_MS;
+    if remaining_bytes == 0 {
+        return format!("You've used up your quota, your window resets in {reset_days:.1} days.");
+    }
+    if rate < 1.0 {
+        return format!(
+            "There's no traffic at the moment, your window resets in {reset_days:.1} days."
+        );
+    }
+    let quota_days = remaining_bytes as f64 / rate * 1000.0 / DAY_MS;
+    let verdict = if quota_days >= reset_days {
+        "so you're on track".to_string()
+    } else {
+        format!(
+            "so you'll hit the cap {:.1} days early",
+            reset_days - quota_days
+        )
+    };
+    format!("At your current average rate you'll use your remaining quota in {quota_days:.1} days; your window resets in {reset_days:.1} days, {verdict}.")
+}
+
+static STDIN_CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// set by `--no-default-config`, a missing config is an error instead of getting created
+static NO_DEFAULT_CONFIG: AtomicBool = AtomicBool::new(false);
+
+/// set by `--no-persist` or `ephemeral` in the config
+static EPHEMERAL: AtomicBool = AtomicBool::new(false);
+
+/// Whether the daemon runs entirely in memory: the state starts from the current counters and
+/// neither it, the history nor a default config get written. The control socket gets created in
+/// the runtime directory under the process id.
+fn ephemeral() -> bool {
+    EPHEMERAL.load(Ordering::SeqCst)
+}
+
+/// Removes `--config -` or `--config-stdin` from `args` and returns whether one of them was given.
+fn take_config_stdin_flag(args: &mut Vec<String>) -> bool {
+    if let Some(pos) = args.iter().position(|arg| arg == "--config-stdin") {
+        args.remove(pos);
+        return true;
     }
+    match args.iter().position(|arg| arg == "--config") {
+        Some(pos) if args.get(pos + 1).map(String::as_str) == Some("-") => {
+            args.drain(pos..pos + 2);
+            true
+        }
+        Some(_) => {
+            eprintln!("Usage: --config - (reads the config from stdin)");
+            process::exit(1);
+        }
+        None => false,
+    }
+}
+
+fn config_path(file: &str) -> PathBuf {
+    dirs::config_dir()
+        .map(|mut dir| {
+            dir.push("traffic_tracker");
+            dir.push(file);
+            dir
+        })
+        .unwrap_or_else(|| PathBuf::from_str("./traffic_tracker").unwrap().join(file))
 }
 
 fn current_time_millis() -> u128 {
@@ -254,6 +1842,378 @@ fn current_time_millis() -> u128 {
     duration_since_epoch.as_millis()
 }
 
+fn format_millis(ms: u64) -> String {
+    chrono::DateTime::from_timestamp_millis(ms as i64)
+        .map(|time| {
+            time.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| ms.to_string())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+enum LogFormat {
+    #[default]
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+struct LogSettings {
+    label: String,
+    format: LogFormat,
+}
+
+static LOG_SETTINGS: OnceLock<LogSettings> = OnceLock::new();
+
+/// Sets up the instance label and format of all following log lines.
+fn init_log(config: &Config) {
+    let label = config.instance_label.clone().unwrap_or_else(hostname);
+    let _ = LOG_SETTINGS.set(LogSettings {
+        label,
+        format: config.log_format,
+    });
+}
+
+fn instance_label() -> Option<&'static str> {
+    LOG_SETTINGS.get().map(|settings| settings.label.as_str())
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|byte| *byte == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
 fn log(val: &str) {
-    println!("[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), val);
+    let time = Local::now().format("%Y-%m-%d %H:%M:%S");
+    match LOG_SETTINGS.get() {
+        None => println!("[{}] {}", time, val),
+        Some(LogSettings {
+            label,
+            format: LogFormat::Text,
+        }) => println!("[{}] [{}] {}", time, label, val),
+        Some(LogSettings {
+            label,
+            format: LogFormat::Json,
+        }) => println!(
+            "{}",
+            serde_json::json!({ "time": time.to_string(), "instance": label, "message": val })
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        env, fs, process,
+        sync::{Mutex, MutexGuard, Once, PoisonError},
+        thread,
+    };
+
+    use num_bigint::BigUint;
+
+    use super::{
+        current_time_millis, follow_updates, recheck_zeros, Boost, Config, Meta, Pause, Usage,
+    };
+    use crate::fixtures::counters;
+
+    /// Points the config directory to a temporary one and keeps the tests using it from running
+    /// concurrently.
+    fn state_dir() -> MutexGuard<'static, ()> {
+        static LOCK: Mutex<()> = Mutex::new(());
+        static INIT: Once = Once::new();
+        let guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        INIT.call_once(|| {
+            let dir = env::temp_dir().join(format!("traffic_tracker_test_{}", process::id()));
+            fs::create_dir_all(dir.join("traffic_tracker")).unwrap();
+            env::set_var("XDG_CONFIG_HOME", dir);
+        });
+        guard
+    }
+
+    fn stored() -> Meta {
+        serde_json::from_slice(&fs::read(Meta::path()).unwrap()).unwrap()
+    }
+
+    fn used_bytes(meta: &Meta, curr_bytes: u64, config: &Config) -> u64 {
+        Usage::compute(meta, &BigUint::from(curr_bytes), config).used_bytes
+    }
+
+    #[test]
+    fn rereads_a_single_spurious_zero() {
+        let previous = BTreeMap::from([("eth0".to_string(), 1000)]);
+        let mut read = counters(&[("eth0", 0)]);
+        recheck_zeros(&mut read, &previous, |_| 1200);
+        assert_eq!(read, counters(&[("eth0", 1200)]));
+        // a confirmed zero is a genuine reset
+        let mut read = counters(&[("eth0", 0)]);
+        recheck_zeros(&mut read, &previous, |_| 0);
+        assert_eq!(read, counters(&[("eth0", 0)]));
+    }
+
+    #[test]
+    fn rereads_only_the_zeroed_interface() {
+        let previous = BTreeMap::from([("eth0".to_string(), 1000), ("wlan0".to_string(), 500)]);
+        let mut read = counters(&[("eth0", 0), ("wlan0", 600)]);
+        recheck_zeros(&mut read, &previous, |interface| {
+            assert_eq!(interface, "eth0");
+            1100
+        });
+        assert_eq!(read, counters(&[("eth0", 1100), ("wlan0", 600)]));
+    }
+
+    #[test]
+    fn accepts_all_interfaces_resetting_together() {
+        let previous = BTreeMap::from([("eth0".to_string(), 1000), ("wlan0".to_string(), 500)]);
+        let mut read = counters(&[("eth0", 0), ("wlan0", 0)]);
+        recheck_zeros(&mut read, &previous, |_| {
+            panic!("reread after a genuine reset")
+        });
+        assert_eq!(read, counters(&[("eth0", 0), ("wlan0", 0)]));
+    }
+
+    #[test]
+    fn rounds_usage_up_to_the_quantum() {
+        let config = Config {
+            accounting_quantum_bytes: 1000,
+            ..Config::defaults()
+        };
+        let meta = Meta::fresh("0".to_string(), &config);
+        assert_eq!(used_bytes(&meta, 0, &config), 0);
+        assert_eq!(used_bytes(&meta, 1, &config), 1000);
+        assert_eq!(used_bytes(&meta, 1000, &config), 1000);
+        assert_eq!(used_bytes(&meta, 1500, &config), 2000);
+        // the correction applies before rounding
+        let config = Config {
+            overhead_correction: 1.5,
+            ..config
+        };
+        assert_eq!(used_bytes(&meta, 1400, &config), 3000);
+    }
+
+    #[test]
+    fn refunds_the_uncounted_usage_first() {
+        let config = Config::defaults();
+        let mut meta = Meta::fresh("0".to_string(), &config);
+        meta.usage_offset_bytes = 5000;
+        assert_eq!(meta.refund(3000, &BigUint::from(1000u32)), 3000);
+        assert_eq!(meta.usage_offset_bytes, 2000);
+        assert_eq!(used_bytes(&meta, 1000, &config), 3000);
+        // the usage can't go below zero
+        assert_eq!(meta.refund(10_000, &BigUint::from(1000u32)), 3000);
+        assert_eq!(used_bytes(&meta, 1000, &config), 0);
+    }
+
+    fn burst_config() -> Config {
+        Config {
+            max_bytes: Some(10_000),
+            burst_allowance_bytes: 1000,
+            burst_refill_ms: 60_000,
+            ..Config::defaults()
+        }
+    }
+
+    const BURST_START_MS: u64 = 1_700_000_000_000;
+
+    fn absorbs(meta: &mut Meta, curr_bytes: u64, now_ms: u64, config: &Config) -> bool {
+        let usage = Usage::compute(meta, &BigUint::from(curr_bytes), config);
+        // the bucket starts out empty and fills up at the first check of a window
+        meta.absorb_burst(&usage, BURST_START_MS + now_ms, config)
+    }
+
+    #[test]
+    fn lets_a_short_burst_pass() {
+        let config = burst_config();
+        let mut meta = Meta::fresh("0".to_string(), &config);
+        assert!(absorbs(&mut meta, 9000, 1000, &config));
+        assert!(absorbs(&mut meta, 10_800, 2000, &config));
+        assert!(absorbs(&mut meta, 10_800, 3000, &config));
+        assert!(!absorbs(&mut meta, 11_200, 4000, &config));
+    }
+
+    #[test]
+    fn limits_sustained_overage() {
+        let config = burst_config();
+        let mut meta = Meta::fresh("0".to_string(), &config);
+        assert!(absorbs(&mut meta, 10_000, 1000, &config));
+        // the allowance doesn't replenish while over the cap however slow the excess grows
+        assert!(absorbs(&mut meta, 10_500, 61_000, &config));
+        assert!(absorbs(&mut meta, 11_000, 121_000, &config));
+        assert!(!absorbs(&mut meta, 11_001, 181_000, &config));
+        // a higher cap brings the usage under it, the allowance replenishes from then on
+        let config = Config {
+            max_bytes: Some(20_000),
+            ..config
+        };
+        assert!(absorbs(&mut meta, 11_001, 211_000, &config));
+        assert!(absorbs(&mut meta, 20_500, 212_000, &config));
+        assert!(!absorbs(&mut meta, 20_501, 213_000, &config));
+    }
+
+    #[test]
+    fn persists_the_highest_reading() {
+        let _dir = state_dir();
+        let meta = Meta::fresh("0".to_string(), &Config::defaults());
+        let writers = ["100", "200"].map(|saved| {
+            let meta = Meta {
+                last_saved_bytes: saved.to_string(),
+                ..meta.clone()
+            };
+            thread::spawn(move || meta.flush())
+        });
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(stored().last_saved_bytes, "200");
+        // a thread with an older reading storing later doesn't move it backwards
+        Meta {
+            last_saved_bytes: "150".to_string(),
+            ..meta
+        }
+        .flush();
+        assert_eq!(stored().last_saved_bytes, "200");
+    }
+
+    /// State with a boost of 1000 bytes and counting paused, both until `until_ms`.
+    fn overridden(until_ms: u64, config: &Config) -> Meta {
+        Meta {
+            boost: Some(Boost {
+                bytes: 1000,
+                expires_at_ms: until_ms,
+            }),
+            pause: Some(Pause {
+                started_at_ms: until_ms - 120_000,
+                until_ms: Some(until_ms),
+                gates_baseline: true,
+            }),
+            ..Meta::fresh("0".to_string(), config)
+        }
+    }
+
+    #[test]
+    fn restores_overrides_after_a_restart() {
+        let _dir = state_dir();
+        let config = Config::defaults();
+        let now = current_time_millis() as u64;
+        overridden(now + 60_000, &config).flush();
+        let mut restarted = Meta::load(&config);
+        restarted.restore_overrides();
+        assert_eq!(restarted.boost_bytes(now), 1000);
+        assert_eq!(
+            Usage::compute(&restarted, &BigUint::ZERO, &config).max_bytes,
+            config.max_bytes() + 1000
+        );
+        let pause = restarted.pause.as_ref().unwrap();
+        assert_eq!(pause.started_at_ms, now - 60_000);
+        assert_eq!(pause.until_ms, Some(now + 60_000));
+        assert!(restarted.counting_paused(now));
+    }
+
+    #[test]
+    fn drops_overrides_which_expired_while_not_running() {
+        let _dir = state_dir();
+        let config = Config::defaults();
+        let now = current_time_millis() as u64;
+        overridden(now - 1, &config).flush();
+        let mut restarted = Meta::load(&config);
+        restarted.restore_overrides();
+        assert!(restarted.boost.is_none());
+        assert!(stored().boost.is_none());
+        assert_eq!(
+            Usage::compute(&restarted, &BigUint::ZERO, &config).max_bytes,
+            config.max_bytes()
+        );
+        assert!(restarted.pause.is_none());
+        assert!(stored().pause.is_none());
+    }
+
+    #[test]
+    fn keeps_paused_traffic_out_of_the_window() {
+        let config = Config::defaults();
+        let now = current_time_millis() as u64;
+        let mut meta = overridden(now + 60_000, &config);
+        meta.account(&BigUint::from(500u32), now, 12, &config);
+        assert_eq!(used_bytes(&meta, 500, &config), 0);
+        // an enforcement pause keeps counting
+        meta.pause.as_mut().unwrap().gates_baseline = false;
+        meta.account(&BigUint::from(300u32), now, 12, &config);
+        assert_eq!(used_bytes(&meta, 800, &config), 300);
+        assert!(meta.paused(now));
+        assert!(!meta.paused(now + 60_000));
+    }
+
+    #[test]
+    fn pauses_counting_while_updates_run() {
+        let _dir = state_dir();
+        let config = Config::defaults();
+        let now = current_time_millis() as u64;
+        let mut meta = Meta::fresh("0".to_string(), &config);
+        follow_updates(&mut meta, true, &config);
+        assert!(meta.counting_paused(now + 24 * 60 * 60 * 1000));
+        assert!(stored().pause.is_some_and(|pause| pause.until_ms.is_none()));
+        follow_updates(&mut meta, false, &config);
+        assert!(meta.pause.is_none());
+        // a pause set by the `pause` command outlasts the updates
+        let mut meta = overridden(now + 60_000, &config);
+        follow_updates(&mut meta, true, &config);
+        follow_updates(&mut meta, false, &config);
+        assert_eq!(meta.pause.unwrap().until_ms, Some(now + 60_000));
+    }
+
+    #[test]
+    fn keeps_the_usage_when_the_wan_interface_changes() {
+        let config = Config::defaults();
+        let mut meta = Meta::fresh("0".to_string(), &config);
+        meta.advance(&counters(&[("eth0", 10_000)]), &config);
+        let curr_bytes = meta.advance(&counters(&[("eth0", 12_000)]), &config);
+        assert_eq!(curr_bytes, BigUint::from(2000u32));
+        // the new interface has sent plenty before it got counted
+        meta.advance(&counters(&[("wwan0", 5_000_000)]), &config);
+        let curr_bytes = meta.advance(&counters(&[("wwan0", 5_000_500)]), &config);
+        assert_eq!(curr_bytes, BigUint::from(2500u32));
+        assert_eq!(Usage::compute(&meta, &curr_bytes, &config).used_bytes, 2500);
+    }
+
+    #[test]
+    fn migrates_states_from_before_the_counter() {
+        let _dir = state_dir();
+        let config = Config::defaults();
+        let meta = Meta {
+            starting_bytes: "1000000".to_string(),
+            last_saved_bytes: "1500000".to_string(),
+            ..Meta::fresh("0".to_string(), &config)
+        };
+        let mut raw = serde_json::to_value(&meta).unwrap();
+        raw.as_object_mut().unwrap().remove("counter");
+        fs::write(Meta::path(), raw.to_string()).unwrap();
+        let mut meta = Meta::load(&config);
+        // the counted interfaces may have changed in the meantime
+        let curr_bytes = meta.advance(&counters(&[("eth0", 42)]), &config);
+        assert_eq!(curr_bytes, BigUint::from(1_500_000u32));
+        assert_eq!(
+            Usage::compute(&meta, &curr_bytes, &config).used_bytes,
+            500_000
+        );
+    }
+
+    #[test]
+    fn reads_the_cap_from_a_credential_file() {
+        let file = env::temp_dir().join(format!("traffic_tracker_cap_{}", process::id()));
+        fs::write(&file, "42000\n").unwrap();
+        let mut raw = serde_json::to_value(Config::defaults()).unwrap();
+        raw.as_object_mut().unwrap().remove("max_bytes");
+        raw["max_bytes_file"] = file.to_str().unwrap().into();
+        let config = serde_json::from_value::<Config>(raw).unwrap();
+        // a left out cap is told apart from a cap of 0
+        assert_eq!(config.max_bytes, None);
+        assert_eq!(config.resolve_credentials().max_bytes, Some(42_000));
+        fs::remove_file(file).unwrap();
+    }
 }
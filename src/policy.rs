@@ -0,0 +1,59 @@
+//! Protocol for delegating enforcement decisions to an external service.
+//!
+//! On every check the daemon connects to the configured unix socket, sends the current
+//! [`Usage`] as a single JSON line and reads back a single line containing one of:
+//!
+//! - `throttle`: limit the traffic to `lower_limit_bytes`
+//! - `release`: remove the limitation
+//! - `rate=N`: limit the traffic to `N` bytes per second
+//!
+//! If the service can't be reached or doesn't answer within the configured timeout
+//! the built-in policy is used for that check.
+
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+use crate::Usage;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Decision {
+    /// limit the traffic to the given amount of bytes per second
+    Throttle(u64),
+    Release,
+}
+
+/// Asks the service at `socket` for a decision, `throttle` maps to `lower_limit_bytes`.
+pub fn query(
+    socket: &Path,
+    timeout_ms: u64,
+    usage: &Usage,
+    lower_limit_bytes: u64,
+) -> io::Result<Decision> {
+    let timeout = Some(Duration::from_millis(timeout_ms));
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    stream.write_all(serde_json::to_string(usage)?.as_bytes())?;
+    stream.write_all(b"\n")?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    parse(line.trim(), lower_limit_bytes)
+}
+
+fn parse(raw: &str, lower_limit_bytes: u64) -> io::Result<Decision> {
+    match raw {
+        "throttle" => Ok(Decision::Throttle(lower_limit_bytes)),
+        "release" => Ok(Decision::Release),
+        _ => raw
+            .strip_prefix("rate=")
+            .and_then(|rate| rate.parse().ok())
+            .map(Decision::Throttle)
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, format!("invalid decision `{raw}`"))
+            }),
+    }
+}
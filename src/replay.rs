@@ -0,0 +1,206 @@
+//! Replays a recorded byte trace through the accounting and throttling decisions without touching
+//! the network, to validate a config against real traffic patterns before deploying it.
+//!
+//! Every line of a trace holds a unix timestamp in milliseconds and the total of sent bytes at
+//! that time, separated by whitespace or a comma. Empty lines and lines starting with `#` are
+//! skipped. The recorded traffic isn't affected by the limitation, so the timeline shows when the
+//! daemon would have acted, not how the traffic would have reacted. The decisions are the ones of
+//! [`check`], external policies aren't consulted though.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use chrono::{Local, TimeZone, Timelike};
+
+use crate::{
+    check::{self, Outcome, Shaper},
+    format_millis, next_local_midnight_ms, zero_bytes, Config, Meta, Usage,
+};
+
+fn parse(raw: &str) -> Result<Vec<(u64, u64)>, String> {
+    raw.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| {
+            let columns = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|column| !column.is_empty())
+                .collect::<Vec<_>>();
+            match columns[..] {
+                [ms, bytes] => ms.parse().ok().zip(bytes.parse().ok()),
+                _ => None,
+            }
+            .ok_or(format!(
+                "Line {}: expected `<unix ms> <total bytes>`",
+                i + 1
+            ))
+        })
+        .collect()
+}
+
+pub fn run(trace: &Path, config_file: Option<&Path>) {
+    let samples = match fs::read_to_string(trace)
+        .map_err(|err| err.to_string())
+        .and_then(|raw| parse(&raw))
+    {
+        Ok(samples) if !samples.is_empty() => samples,
+        Ok(_) => {
+            eprintln!("The trace {} contains no samples", trace.display());
+            return;
+        }
+        Err(err) => {
+            eprintln!("Couldn't read the trace {}: {err}", trace.display());
+            return;
+        }
+    };
+    let config = match config_file {
+        Some(path) => match fs::read(path)
+            .map_err(|err| err.to_string())
+            .and_then(|raw| serde_json::from_slice::<Config>(&raw).map_err(|err| err.to_string()))
+        {
+            Ok(config) => config.resolve_credentials(),
+            Err(err) => {
+                eprintln!("Couldn't read the config {}: {err}", path.display());
+                return;
+            }
+        },
+        None => Config::load(),
+    }
+    .for_connection(None);
+
+    let (start_ms, start_bytes) = samples[0];
+    let mut meta = Meta::fresh(zero_bytes(), &config);
+    meta.reset_at_ms = start_ms + config.capture_timeframe_ms;
+    meta.daily_reset_at_ms = next_local_midnight_ms(start_ms);
+    // the first reading only sets the baseline of the counter
+    let mut curr_bytes = meta.advance(&counters(start_bytes), &config);
+    let mut windows = vec![];
+    let mut throttles = 0;
+    let mut throttled_ms = 0;
+    let mut throttled_since_ms = None;
+    let mut last_ms = start_ms;
+    let mut observed_over_cap = false;
+
+    let print = |ms: u64, event: &str| println!("[{}] {event}", format_millis(ms));
+    for (ms, bytes) in samples {
+        if ms < last_ms {
+            eprintln!("Samples have to be ordered by time, {ms} comes after {last_ms}");
+            return;
+        }
+        let window = meta.reset_at_ms;
+        while ms >= meta.reset_at_ms {
+            if let Some(since) = throttled_since_ms.take() {
+                throttled_ms += meta.reset_at_ms.saturating_sub(since);
+                print(meta.reset_at_ms, "tc: limitation removed");
+            }
+            let usage = Usage::compute(&meta, &curr_bytes, &config);
+            windows.push((meta.reset_at_ms, usage.used_bytes, throttled_ms));
+            print(
+                meta.reset_at_ms,
+                &format!("Window over, {} bytes used", usage.used_bytes),
+            );
+            throttled_ms = 0;
+            let reset_at_ms = meta.reset_at_ms;
+            let counter = meta.counter.clone();
+            meta = Meta::fresh(curr_bytes.to_string(), &config);
+            meta.counter = counter;
+            meta.usage_offset_bytes = 0;
+            meta.reset_at_ms = reset_at_ms + config.capture_timeframe_ms;
+            meta.daily_reset_at_ms = next_local_midnight_ms(ms);
+        }
+        // a counter going backwards counts like a reboot of the machine
+        let previous = curr_bytes;
+        curr_bytes = meta.advance(&counters(bytes), &config);
+        // like the daemon the traffic since the previous sample isn't booked into a new day or window
+        if !meta.roll_over_day(ms) && meta.reset_at_ms == window {
+            let hour = Local.timestamp_millis_opt(ms as i64).unwrap().hour();
+            meta.account(&(&curr_bytes - previous), ms, hour, &config);
+        }
+        last_ms = ms;
+
+        let usage = Usage::compute(&meta, &curr_bytes, &config);
+        let over_cap = check::over_cap(&mut meta, &usage, ms, &config);
+        let decision = check::built_in(&meta, &usage, over_cap, ms, &config);
+        match check::apply(
+            &mut meta,
+            decision,
+            over_cap,
+            ms,
+            &mut observed_over_cap,
+            &config,
+            &mut DryRun,
+        ) {
+            Outcome::Trial { rate, first: true } => print(
+                ms,
+                &format!("Trial period: would limit to {rate} bytes per second"),
+            ),
+            Outcome::Limited { rate, previous, .. } => {
+                print(
+                    ms,
+                    &format!(
+                        "tc: limiting to {rate} bytes per second, {} of {} bytes used",
+                        usage.used_bytes, usage.max_bytes
+                    ),
+                );
+                if previous.is_none() {
+                    throttles += 1;
+                    throttled_since_ms = Some(ms);
+                }
+            }
+            Outcome::Released => {
+                if let Some(since) = throttled_since_ms.take() {
+                    throttled_ms += ms.saturating_sub(since);
+                }
+                print(
+                    ms,
+                    &format!(
+                        "tc: limitation removed, {} of {} bytes used",
+                        usage.used_bytes, usage.max_bytes
+                    ),
+                );
+            }
+            _ => {}
+        }
+    }
+    if let Some(since) = throttled_since_ms {
+        throttled_ms += last_ms.saturating_sub(since);
+    }
+    let usage = Usage::compute(&meta, &curr_bytes, &config);
+    windows.push((last_ms, usage.used_bytes, throttled_ms));
+
+    println!();
+    println!(
+        "Summary: {} window(s), limitation applied {throttles} time(s)",
+        windows.len()
+    );
+    for (ended_at_ms, used_bytes, throttled_ms) in windows {
+        println!(
+            "window until {}: {used_bytes} of {} bytes used, throttled for {}s",
+            format_millis(ended_at_ms),
+            config.max_bytes(),
+            throttled_ms / 1000
+        );
+    }
+}
+
+/// The trace stands in for the interface counters.
+fn counters(bytes: u64) -> [(String, u64); 1] {
+    [(TRACE_COUNTER.to_string(), bytes)]
+}
+
+const TRACE_COUNTER: &str = "trace";
+
+/// Stands in for `tc`, the limitation always applies.
+struct DryRun;
+
+impl Shaper for DryRun {
+    fn limit(
+        &mut self,
+        _rate: u64,
+        _rates: &BTreeMap<String, f64>,
+        _config: &Config,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn release(&mut self, _config: &Config) {}
+}
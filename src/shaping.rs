@@ -0,0 +1,610 @@
+//! Applying and removing the traffic limitation via `tc`.
+
+use std::{
+    collections::BTreeMap,
+    process::Command,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{current_time_millis, format_millis, interfaces, log, wan, Config, Meta};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ThrottleSplit {
+    /// every interface gets limited to the full rate
+    #[default]
+    PerInterface,
+    /// the rate is split equally over the counted interfaces
+    Shared,
+    /// the rate is split over the counted interfaces by their share of the recent usage
+    Proportional,
+}
+
+/// How traffic gets discouraged while over the cap.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Enforcement {
+    /// limit the bandwidth to the throttle rate
+    #[default]
+    Rate,
+    /// keep the bandwidth but delay (and optionally drop) every packet, which makes large
+    /// transfers tedious while interactive use stays okay
+    Netem,
+    /// always limit the bandwidth so that the remaining quota lasts exactly until the window
+    /// resets, `lower_limit_bytes` is the minimum rate
+    PaceToReset,
+}
+
+/// What happens to an interface while over the cap.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfacePolicy {
+    /// apply the configured `enforcement`
+    #[default]
+    Shape,
+    /// drop all packets leaving the interface
+    Block,
+    /// leave the interface alone
+    Observe,
+}
+
+/// Policy of `interface`, from the first pattern of `interface_policies` matching it.
+pub fn policy(config: &Config, interface: &str) -> InterfacePolicy {
+    config
+        .interface_policies
+        .iter()
+        .find(|(pattern, _)| interfaces::glob_match(pattern, interface))
+        .map(|(_, policy)| *policy)
+        .unwrap_or_default()
+}
+
+/// no interface gets limited below this many bytes per second when splitting the limit
+const MIN_SPLIT_RATE: u64 = 1024;
+
+/// how much the usage share of an interface has to change before the limit gets split anew
+const SHARE_HYSTERESIS: f64 = 0.1;
+
+/// with `lower_limit_bytes` set to 0 traffic gets limited to this fraction of the line rate
+const AUTO_RATE_DIVISOR: u64 = 10;
+
+/// the automatically derived rate we logged last
+static LAST_AUTO_RATE: AtomicU64 = AtomicU64::new(0);
+
+/// Throttle rate used with `lower_limit_bytes` set to 0: a tenth of the line rate of the slowest
+/// counted interface, or `auto_rate_fallback_bytes` if none of them reports its speed.
+pub fn auto_rate(config: &Config) -> u64 {
+    let rate = interfaces::counted(config)
+        .iter()
+        .filter_map(|interface| interfaces::speed_bytes(interface))
+        .min()
+        .map(|speed| speed / AUTO_RATE_DIVISOR)
+        .unwrap_or(config.auto_rate_fallback_bytes);
+    if LAST_AUTO_RATE.swap(rate, Ordering::SeqCst) != rate {
+        log(&format!("Throttle rate automatically set to {rate} bytes"));
+    }
+    rate
+}
+
+/// how much the pace has to change before the limitation gets applied anew
+const PACE_HYSTERESIS: f64 = 0.1;
+
+/// Rate at which `remaining_bytes` last for `remaining_ms`, at least `min_rate`. Returns the
+/// `applied` rate while the pace stays close to it so the qdisc isn't replaced on every check.
+pub fn pace_rate(
+    remaining_bytes: u64,
+    remaining_ms: u64,
+    min_rate: u64,
+    applied: Option<u64>,
+) -> u64 {
+    let rate = (remaining_bytes as u128 * 1000 / remaining_ms.max(1) as u128)
+        .try_into()
+        .unwrap_or(u64::MAX)
+        .max(min_rate);
+    match applied {
+        Some(applied)
+            if applied >= min_rate
+                && (rate.abs_diff(applied) as f64) < applied as f64 * PACE_HYSTERESIS =>
+        {
+            applied
+        }
+        _ => rate,
+    }
+}
+
+/// Interfaces the limitation gets applied to, only the WAN interface if there is one.
+/// Observed interfaces are left out.
+pub fn targets(config: &Config) -> Vec<String> {
+    let targets = match config.throttle_split {
+        _ if wan::interface(config).is_some() => interfaces::counted(config),
+        ThrottleSplit::PerInterface => interfaces::all(),
+        ThrottleSplit::Shared | ThrottleSplit::Proportional => interfaces::counted(config),
+    };
+    targets
+        .into_iter()
+        .filter(|interface| policy(config, interface) != InterfacePolicy::Observe)
+        .collect()
+}
+
+/// Share of the limit every shaped target interface gets, `rates` are the recent send rates per
+/// interface.
+pub fn shares(config: &Config, rates: &BTreeMap<String, f64>) -> BTreeMap<String, f64> {
+    let targets = targets(config)
+        .into_iter()
+        .filter(|interface| policy(config, interface) == InterfacePolicy::Shape)
+        .collect::<Vec<_>>();
+    let total = targets
+        .iter()
+        .map(|interface| rates.get(interface).copied().unwrap_or(0.0))
+        .sum::<f64>();
+    targets
+        .iter()
+        .map(|interface| {
+            let share = match config.throttle_split {
+                ThrottleSplit::PerInterface => 1.0,
+                ThrottleSplit::Proportional if total > 0.0 => {
+                    rates.get(interface).copied().unwrap_or(0.0) / total
+                }
+                _ => 1.0 / targets.len() as f64,
+            };
+            (interface.clone(), share)
+        })
+        .collect()
+}
+
+pub fn shares_shifted(applied: &BTreeMap<String, f64>, current: &BTreeMap<String, f64>) -> bool {
+    applied.len() != current.len()
+        || current.iter().any(|(interface, share)| {
+            applied
+                .get(interface)
+                .is_none_or(|applied| (applied - share).abs() > SHARE_HYSTERESIS)
+        })
+}
+
+/// Applies the limitation on every target interface. If a part of it can't be applied the rest
+/// gets removed again, so the traffic is either limited as configured or not at all.
+///
+/// If the kernel lacks a module the limitation needs, the usage only gets observed for
+/// [`MISSING_MODULE_BACKOFF_MS`] before trying again.
+pub fn enable_lower_bandwidth(
+    limit: u64,
+    config: &Config,
+    rates: &BTreeMap<String, f64>,
+) -> Result<(), String> {
+    let now = current_time_millis() as u64;
+    let observing_until_ms = OBSERVING_UNTIL_MS.load(Ordering::SeqCst);
+    if now < observing_until_ms {
+        return Err(format!(
+            "only observing until {} as the kernel lacks a module",
+            format_millis(observing_until_ms)
+        ));
+    }
+    if !PREPARED.swap(true, Ordering::SeqCst) {
+        run_hooks("pre-throttle", &config.pre_throttle_commands);
+    }
+    AUTO_LOAD_MODULES.store(config.auto_load_modules, Ordering::SeqCst);
+    let Err(err) = apply(limit, config, rates) else {
+        return Ok(());
+    };
+    if let Some(module) = err.missing_module {
+        let until_ms = now + MISSING_MODULE_BACKOFF_MS;
+        OBSERVING_UNTIL_MS.store(until_ms, Ordering::SeqCst);
+        log(&format!(
+            "!!! The kernel lacks {module}, traffic is NOT limited, only observing the usage until {} !!!",
+            format_millis(until_ms)
+        ));
+    } else {
+        log(&format!(
+            "!!! Couldn't apply the limitation, traffic is NOT limited: {} !!!",
+            err.message
+        ));
+    }
+    disable_lowered_bandwidth(config);
+    Err(err.message)
+}
+
+/// time the usage only gets observed after the limitation failed for a missing kernel module
+const MISSING_MODULE_BACKOFF_MS: u64 = 60 * 60 * 1000;
+
+/// set while only observing, see [`enable_lower_bandwidth`]
+static OBSERVING_UNTIL_MS: AtomicU64 = AtomicU64::new(0);
+
+fn apply(limit: u64, config: &Config, rates: &BTreeMap<String, f64>) -> Result<(), TcError> {
+    for interface in targets(config) {
+        if policy(config, &interface) == InterfacePolicy::Block {
+            log(&format!("Blocking network traffic on {interface}..."));
+            tc(&[
+                "qdisc", "add", "dev", &interface, "root", "netem", "loss", "100%",
+            ])?;
+        }
+    }
+    if config.enforcement == Enforcement::Netem {
+        log(&format!(
+            "Delaying network traffic by {}ms...",
+            config.netem_delay_ms
+        ));
+        for interface in shares(config, rates).into_keys() {
+            delay(&interface, config)?;
+        }
+        return Ok(());
+    }
+    log(&format!("Limiting network traffic to {limit} bytes..."));
+    for (interface, share) in shares(config, rates) {
+        let limit = if config.throttle_split == ThrottleSplit::PerInterface {
+            limit
+        } else {
+            ((limit as f64 * share) as u64).max(MIN_SPLIT_RATE)
+        };
+        if config.throttle_split != ThrottleSplit::PerInterface {
+            log(&format!("Limiting {interface} to {limit} bytes..."));
+        }
+        if config.throttle_dscp_match.is_empty() {
+            limit_rate(&interface, limit, config)?;
+        } else {
+            limit_dscp(&interface, limit, config)?;
+        }
+    }
+    Ok(())
+}
+
+/// Limits all traffic leaving `interface` with `tbf`, or with a single `htb` class on kernels
+/// lacking `sch_tbf`.
+fn limit_rate(interface: &str, limit: u64, config: &Config) -> Result<(), TcError> {
+    let rate = (limit * 8).to_string();
+    let burst = config.burst_buffer_size.to_string();
+    let tbf = tc(&[
+        "qdisc",
+        "add",
+        "dev",
+        interface,
+        "root",
+        "tbf",
+        "rate",
+        &rate,
+        "burst",
+        &burst,
+        "latency",
+        &config.buffer_latency_ms.to_string(),
+    ]);
+    match tbf {
+        Err(TcError {
+            missing_module: Some(_),
+            ..
+        }) => {
+            log(&format!("Limiting {interface} with htb instead of tbf..."));
+            HTB_FALLBACK.store(true, Ordering::SeqCst);
+            tc(&[
+                "qdisc", "add", "dev", interface, "root", "handle", "1:", "htb", "default", "10",
+            ])?;
+            tc(&[
+                "class", "add", "dev", interface, "parent", "1:", "classid", "1:10", "htb", "rate",
+                &rate, "ceil", &rate, "burst", &burst,
+            ])
+        }
+        tbf => tbf,
+    }
+}
+
+/// set once `tbf` turned out to be unavailable and [`limit_rate`] used `htb` instead
+static HTB_FALLBACK: AtomicBool = AtomicBool::new(false);
+
+/// Delays every packet leaving `interface` by `netem_delay_ms` and drops `netem_loss_percent` of them.
+fn delay(interface: &str, config: &Config) -> Result<(), TcError> {
+    let delay = format!("{}ms", config.netem_delay_ms);
+    let mut args = vec![
+        "qdisc", "add", "dev", interface, "root", "netem", "delay", &delay,
+    ];
+    let loss = format!("{}%", config.netem_loss_percent);
+    if config.netem_loss_percent > 0.0 {
+        args.extend(["loss", &loss]);
+    }
+    tc(&args)
+}
+
+/// Only limits packets matching `throttle_dscp_match`: unmatched traffic goes into the unlimited
+/// default class `1:10` while matched traffic gets filtered into the limited class `1:20`.
+fn limit_dscp(interface: &str, limit: u64, config: &Config) -> Result<(), TcError> {
+    let rate = (limit * 8).to_string();
+    let burst = config.burst_buffer_size.to_string();
+    tc(&[
+        "qdisc", "add", "dev", interface, "root", "handle", "1:", "htb", "default", "10",
+    ])?;
+    tc(&[
+        "class",
+        "add",
+        "dev",
+        interface,
+        "parent",
+        "1:",
+        "classid",
+        "1:10",
+        "htb",
+        "rate",
+        UNLIMITED_RATE,
+    ])?;
+    tc(&[
+        "class", "add", "dev", interface, "parent", "1:", "classid", "1:20", "htb", "rate", &rate,
+        "ceil", &rate, "burst", &burst,
+    ])?;
+    for dscp in &config.throttle_dscp_match {
+        // the DSCP value occupies the upper 6 bits of the ToS / traffic class byte
+        let tos = format!("0x{:02x}", (dscp & 0x3f) << 2);
+        tc(&[
+            "filter", "add", "dev", interface, "parent", "1:", "protocol", "ip", "prio", "1",
+            "u32", "match", "ip", "dsfield", &tos, "0xfc", "flowid", "1:20",
+        ])?;
+        tc(&[
+            "filter", "add", "dev", interface, "parent", "1:", "protocol", "ipv6", "prio", "2",
+            "u32", "match", "ip6", "priority", &tos, "0xfc", "flowid", "1:20",
+        ])?;
+    }
+    Ok(())
+}
+
+const UNLIMITED_RATE: &str = "100gbit";
+
+pub fn disable_lowered_bandwidth(config: &Config) {
+    log("Loosening network traffic restrictions...");
+    let applied = interfaces::all()
+        .into_iter()
+        .filter(|interface| policy(config, interface) != InterfacePolicy::Observe);
+    for interface in applied {
+        // deleting the root qdisc removes it whatever its kind, including all classes and
+        // filters attached to it
+        let _ = tc(&["qdisc", "del", "dev", &interface, "root"]);
+    }
+    run_hooks("post-cleanup", &config.post_cleanup_commands);
+}
+
+static PREPARED: AtomicBool = AtomicBool::new(false);
+
+pub fn run_hooks(kind: &str, commands: &[String]) {
+    for command in commands {
+        match Command::new("sh").args(["-c", command]).output() {
+            Ok(output) => {
+                log(&format!(
+                    "{kind} command `{command}` exited with {}",
+                    output.status
+                ));
+                for line in String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .chain(String::from_utf8_lossy(&output.stderr).lines())
+                {
+                    log(&format!("  {line}"));
+                }
+            }
+            Err(err) => log(&format!("Couldn't run {kind} command `{command}`: {err}")),
+        }
+    }
+}
+
+/// Kind of the root qdisc we apply on `interface` when limiting.
+fn expected_qdisc(config: &Config, interface: &str) -> &'static str {
+    if config.enforcement == Enforcement::Netem
+        || policy(config, interface) == InterfacePolicy::Block
+    {
+        "netem"
+    } else if config.throttle_dscp_match.is_empty() && !HTB_FALLBACK.load(Ordering::SeqCst) {
+        "tbf"
+    } else {
+        "htb"
+    }
+}
+
+/// Returns the kind of the root qdisc currently applied on `interface`.
+pub fn root_qdisc(interface: &str) -> Option<String> {
+    let output = Command::new("tc")
+        .args(["qdisc", "show", "dev", interface])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|line| line.split_whitespace().any(|word| word == "root"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .map(str::to_string)
+}
+
+/// Root qdisc applied on an interface as reported by `tc -j`.
+#[derive(Serialize)]
+pub struct QdiscStatus {
+    interface: String,
+    kind: Option<String>,
+    /// only reported by rate limiting qdiscs like `tbf`
+    rate_bytes: Option<u64>,
+    /// why the state couldn't be determined
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Reports the root qdisc currently applied on every interface the limitation gets applied to.
+pub fn qdisc_status(config: &Config) -> Vec<QdiscStatus> {
+    targets(config)
+        .into_iter()
+        .map(|interface| {
+            let (kind, rate_bytes, error) = match parse_qdisc(&interface) {
+                Ok((kind, rate_bytes)) => (kind, rate_bytes, None),
+                Err(err) => (None, None, Some(err)),
+            };
+            QdiscStatus {
+                interface,
+                kind,
+                rate_bytes,
+                error,
+            }
+        })
+        .collect()
+}
+
+fn parse_qdisc(interface: &str) -> Result<(Option<String>, Option<u64>), String> {
+    let output = Command::new("tc")
+        .args(["-j", "qdisc", "show", "dev", interface])
+        .output()
+        .map_err(|err| format!("couldn't run tc: {err}"))?;
+    let qdiscs = serde_json::from_slice::<Vec<serde_json::Value>>(&output.stdout)
+        .map_err(|err| format!("couldn't parse the output of tc: {err}"))?;
+    let Some(root) = qdiscs
+        .iter()
+        .find(|qdisc| qdisc["root"].as_bool() == Some(true))
+    else {
+        return Ok((None, None));
+    };
+    Ok((
+        root["kind"].as_str().map(str::to_string),
+        root["options"]["rate"].as_u64(),
+    ))
+}
+
+/// Brings the applied qdiscs back in line with the throttle state after they got changed
+/// behind our back, e.g. by an admin running `tc qdisc del` manually.
+pub fn reconcile(meta: &mut Meta, config: &Config) {
+    let targets = targets(config);
+    let limited = interfaces::all()
+        .into_iter()
+        .filter(|interface| {
+            root_qdisc(interface).as_deref() == Some(expected_qdisc(config, interface))
+        })
+        .collect::<Vec<_>>();
+    if meta.throttled && targets.iter().any(|target| !limited.contains(target)) {
+        log("Limitation got removed externally, applying it again...");
+        disable_lowered_bandwidth(config);
+        if enable_lower_bandwidth(meta.applied_rate, config, &meta.interface_rates).is_err() {
+            meta.set_throttled(false);
+        }
+    } else if !meta.throttled && !limited.is_empty() {
+        // this may as well be shaping the admin set up on purpose, so leave it alone
+        log(&format!(
+            "Found our kind of qdisc on {} although not throttled",
+            limited.join(", ")
+        ));
+    }
+}
+
+/// set from `auto_load_modules` whenever the limitation gets applied
+static AUTO_LOAD_MODULES: AtomicBool = AtomicBool::new(false);
+
+/// Why a `tc` invocation failed, the failure got logged already.
+struct TcError {
+    /// kernel module providing the qdisc or filter, if the kernel lacks it
+    missing_module: Option<&'static str>,
+    message: String,
+}
+
+fn tc(args: &[&str]) -> Result<(), TcError> {
+    let Err(err) = run_tc(args) else {
+        return Ok(());
+    };
+    let message = format!("`tc {}` failed: {err}", args.join(" "));
+    let Some(module) = missing_module(&err, args) else {
+        log(&format!("Error running {message}"));
+        return Err(TcError {
+            missing_module: None,
+            message,
+        });
+    };
+    if AUTO_LOAD_MODULES.load(Ordering::SeqCst) {
+        log(&format!("Kernel module {module} is missing, loading it..."));
+        let loaded = Command::new("sudo")
+            .args(["modprobe", module])
+            .status()
+            .is_ok_and(|status| status.success());
+        if loaded && run_tc(args).is_ok() {
+            return Ok(());
+        }
+    }
+    log(&format!("The kernel lacks {module}, {message}"));
+    Err(TcError {
+        missing_module: Some(module),
+        message: format!("the kernel lacks {module}"),
+    })
+}
+
+fn run_tc(args: &[&str]) -> Result<(), String> {
+    let output = Command::new("sudo")
+        .arg("tc")
+        .args(args)
+        .output()
+        .map_err(|err| err.to_string())?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// Kernel module providing the qdisc or filter `args` use, if `err` says it isn't available.
+fn missing_module(err: &str, args: &[&str]) -> Option<&'static str> {
+    // older versions of tc report a missing module like a missing device
+    let unknown = err.contains("kind is unknown")
+        || err.contains("Unknown qdisc")
+        || err.contains("Unknown filter")
+        || err.contains("No such file or directory");
+    if !unknown {
+        return None;
+    }
+    [
+        ("tbf", "sch_tbf"),
+        ("htb", "sch_htb"),
+        ("netem", "sch_netem"),
+        ("u32", "cls_u32"),
+    ]
+    .into_iter()
+    .find(|(kind, _)| args.contains(kind))
+    .map(|(_, module)| module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{missing_module, pace_rate};
+
+    const HOUR_MS: u64 = 60 * 60 * 1000;
+
+    #[test]
+    fn spreads_the_remaining_budget() {
+        assert_eq!(pace_rate(3_600_000, HOUR_MS, 0, None), 1000);
+        assert_eq!(pace_rate(3_600_000, 2 * HOUR_MS, 0, None), 500);
+        assert_eq!(pace_rate(7_200_000, HOUR_MS / 2, 0, None), 4000);
+    }
+
+    #[test]
+    fn clamps_to_the_minimum_rate() {
+        assert_eq!(pace_rate(0, HOUR_MS, 100, None), 100);
+        assert_eq!(pace_rate(3_600, HOUR_MS, 100, None), 100);
+        assert_eq!(pace_rate(3_600_000, HOUR_MS, 100, None), 1000);
+    }
+
+    #[test]
+    fn handles_the_window_ending() {
+        // the remaining budget may be used within the last millisecond
+        assert_eq!(pace_rate(5, 0, 0, None), 5000);
+        assert_eq!(pace_rate(u64::MAX, 1, 0, None), u64::MAX);
+    }
+
+    #[test]
+    fn keeps_the_applied_rate_within_the_hysteresis() {
+        assert_eq!(pace_rate(3_780_000, HOUR_MS, 0, Some(1000)), 1000);
+        assert_eq!(pace_rate(3_420_000, HOUR_MS, 0, Some(1000)), 1000);
+        assert_eq!(pace_rate(3_960_000, HOUR_MS, 0, Some(1000)), 1100);
+        assert_eq!(pace_rate(1_800_000, HOUR_MS, 0, Some(1000)), 500);
+        // the minimum wins over a lower applied rate
+        assert_eq!(pace_rate(0, HOUR_MS, 100, Some(95)), 100);
+        assert_eq!(pace_rate(0, HOUR_MS, 200, Some(95)), 200);
+    }
+
+    #[test]
+    fn detects_missing_qdisc_modules() {
+        let tbf = ["qdisc", "add", "dev", "eth0", "root", "tbf", "rate", "8000"];
+        assert_eq!(
+            missing_module("Error: Specified qdisc kind is unknown.", &tbf),
+            Some("sch_tbf")
+        );
+        assert_eq!(
+            missing_module("RTNETLINK answers: No such file or directory", &tbf),
+            Some("sch_tbf")
+        );
+        assert_eq!(
+            missing_module("RTNETLINK answers: Operation not permitted", &tbf),
+            None
+        );
+    }
+}
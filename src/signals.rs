@@ -0,0 +1,68 @@
+//! Signal handling.
+//!
+//! Handlers only flip an atomic flag, the main loop picks the flags up on its next tick.
+//!
+//! - `SIGUSR1`: panic reset, see [`crate::panic_reset`]
+//! - `SIGUSR2`: logs a status snapshot
+//! - `SIGINT`/`SIGTERM`: handled by subcommands which have to clean up before exiting and by the
+//!   daemon if `drain_timeout_ms` is configured, see [`crate::drain`]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static PANIC_REQUESTED: AtomicBool = AtomicBool::new(false);
+static STATUS_REQUESTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    PANIC_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_sigusr2(_: libc::c_int) {
+    STATUS_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+pub fn install() {
+    unsafe {
+        libc::signal(libc::SIGUSR1, on_sigusr1 as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGUSR2, on_sigusr2 as *const () as libc::sighandler_t);
+    }
+}
+
+/// Returns whether a panic reset was requested and clears the request.
+pub fn take_panic_request() -> bool {
+    PANIC_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+pub fn panic_requested() -> bool {
+    PANIC_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Returns whether a status snapshot was requested and clears the request.
+pub fn take_status_request() -> bool {
+    STATUS_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+pub fn status_requested() -> bool {
+    STATUS_REQUESTED.load(Ordering::SeqCst)
+}
+
+pub fn install_interrupt() {
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as *const () as libc::sighandler_t,
+        );
+        libc::signal(
+            libc::SIGTERM,
+            on_interrupt as *const () as libc::sighandler_t,
+        );
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
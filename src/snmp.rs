@@ -0,0 +1,88 @@
+//! Usage counters for SNMP monitoring, exposed through the `pass_persist` protocol of net-snmp's
+//! `snmpd`, e.g. with `pass_persist .1.3.6.1.4.1.8072.9999.1 /usr/bin/TrafficTracker snmp-pass-persist`.
+//!
+//! Objects below the base OID:
+//! - `.1` usedBytes (counter64)
+//! - `.2` maxBytes (counter64)
+//! - `.3` remainingBytes (counter64)
+//! - `.4` throttled (integer, 1 if throttled)
+
+use std::io::{self, BufRead, Write};
+
+use crate::control;
+
+/// below the `netSnmpPlaypen` subtree which is meant for local experiments
+pub const DEFAULT_BASE_OID: &str = ".1.3.6.1.4.1.8072.9999.1";
+
+const OBJECTS: [(u32, &str, &str); 4] = [
+    (1, "used_bytes", "counter64"),
+    (2, "max_bytes", "counter64"),
+    (3, "remaining_bytes", "counter64"),
+    (4, "throttled", "integer"),
+];
+
+/// Answers requests of `snmpd` on stdin until it closes the pipe.
+pub fn pass_persist(base: &str) {
+    let base = parse_oid(base);
+    let mut lines = io::stdin().lock().lines().map_while(Result::ok);
+    let mut out = io::stdout().lock();
+    while let Some(command) = lines.next() {
+        let response = match command.trim() {
+            "PING" => "PONG\n".to_string(),
+            "get" | "getnext" => {
+                let oid = parse_oid(&lines.next().unwrap_or_default());
+                let object = OBJECTS.iter().find(|(id, _, _)| {
+                    let object_oid = [base.as_slice(), &[*id]].concat();
+                    if command.trim() == "get" {
+                        object_oid == oid
+                    } else {
+                        object_oid > oid
+                    }
+                });
+                match object.and_then(|object| value(object).map(|value| (object, value))) {
+                    Some(((id, _, kind), value)) => {
+                        format!("{}.{id}\n{kind}\n{value}\n", format_oid(&base))
+                    }
+                    None => "NONE\n".to_string(),
+                }
+            }
+            "set" => {
+                // skip the OID and the value
+                lines.next();
+                lines.next();
+                "not-writable\n".to_string()
+            }
+            _ => "NONE\n".to_string(),
+        };
+        if out
+            .write_all(response.as_bytes())
+            .and_then(|_| out.flush())
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Fetches the current value of `object` from the daemon's status.
+fn value((_, field, _): &(u32, &str, &str)) -> Option<String> {
+    let status = control::request("status").ok()?;
+    let usage = serde_json::from_str::<serde_json::Value>(&status).ok()?;
+    match &usage[field] {
+        serde_json::Value::Bool(throttled) => Some(u8::from(*throttled).to_string()),
+        serde_json::Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+fn parse_oid(raw: &str) -> Vec<u32> {
+    raw.trim()
+        .split('.')
+        .filter(|part| !part.is_empty())
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+fn format_oid(oid: &[u32]) -> String {
+    oid.iter().map(|part| format!(".{part}")).collect()
+}
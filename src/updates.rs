@@ -0,0 +1,30 @@
+//! Detection of running package manager updates, see `pause_during_updates`.
+
+use std::{fs, path::Path};
+
+/// process names (as in `/proc/<pid>/comm`, truncated to 15 characters) of package managers
+const PACKAGE_MANAGERS: &[&str] = &[
+    "apt",
+    "apt-get",
+    "aptitude",
+    "dpkg",
+    "unattended-upgr",
+    "dnf",
+    "yum",
+    "pacman",
+    "zypper",
+];
+
+/// lock files which only exist while the package manager is running
+const LOCK_FILES: &[&str] = &["/var/lib/pacman/db.lck"];
+
+pub fn running() -> bool {
+    LOCK_FILES.iter().any(|lock| Path::new(lock).exists())
+        || fs::read_dir("/proc")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().parse::<u32>().is_ok())
+            .filter_map(|entry| fs::read_to_string(entry.path().join("comm")).ok())
+            .any(|comm| PACKAGE_MANAGERS.contains(&comm.trim_end()))
+}
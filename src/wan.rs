@@ -0,0 +1,75 @@
+//! Discovery of the primary WAN interface, which then is the only interface counted and limited.
+//! The discovered interface gets persisted as `wan_interface` in the config file.
+
+use std::{fs, path::Path, sync::Mutex};
+
+use crate::{config_path, connection, ephemeral, interfaces, log, Config, STDIN_CONFIG};
+
+/// the WAN interface currently in use, `wan_interface` of the config until it gets rediscovered
+static CURRENT: Mutex<Option<Option<String>>> = Mutex::new(None);
+
+/// Returns the WAN interface if one is configured or discovery is enabled, discovering it anew
+/// if it disappeared.
+pub fn interface(config: &Config) -> Option<String> {
+    let mut current = CURRENT.lock().unwrap();
+    let wan = current.get_or_insert_with(|| config.wan_interface.clone());
+    let gone = wan
+        .as_ref()
+        .is_none_or(|wan| !Path::new("/sys/class/net").join(wan).exists());
+    if config.auto_discover_wan && gone {
+        let discovered = discover();
+        if discovered != *wan {
+            if let Some(interface) = &discovered {
+                log(&format!("Discovered {interface} as the WAN interface"));
+                persist(interface);
+            }
+            *wan = discovered;
+        }
+    }
+    wan.clone()
+}
+
+/// Discovers the WAN interface again regardless of the current one, for the `rediscover` command.
+pub fn rediscover() -> Option<String> {
+    let discovered = discover();
+    if let Some(interface) = &discovered {
+        log(&format!("Rediscovered {interface} as the WAN interface"));
+        persist(interface);
+    }
+    *CURRENT.lock().unwrap() = Some(discovered.clone());
+    discovered
+}
+
+/// The physical interface carrying the default route with the lowest metric, tunnels carry
+/// the default route as well but their traffic passes the physical interface anyway.
+pub fn discover() -> Option<String> {
+    let routes = connection::default_route_interfaces(connection::MultiDefaultRoute::All);
+    routes
+        .iter()
+        .find(|interface| !interfaces::is_virtual(interface))
+        .or(routes.first())
+        .cloned()
+}
+
+/// Stores `interface` in the config file, keeping everything else as it is.
+fn persist(interface: &str) {
+    if ephemeral() || STDIN_CONFIG.get().is_some() {
+        return;
+    }
+    let path = config_path("config.json");
+    let result = fs::read(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|raw| {
+            serde_json::from_slice::<serde_json::Value>(&raw).map_err(|err| err.to_string())
+        })
+        .and_then(|mut config| {
+            config["wan_interface"] = serde_json::Value::String(interface.to_string());
+            fs::write(&path, serde_json::to_string_pretty(&config).unwrap())
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = result {
+        log(&format!(
+            "Couldn't store the WAN interface in the config: {err}"
+        ));
+    }
+}
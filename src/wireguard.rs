@@ -0,0 +1,73 @@
+//! Counting only the traffic of WireGuard peers via `wg show <interface> transfer`, which excludes
+//! the tunnel overhead and all traffic outside the tunnel.
+
+use std::{
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{log, Config};
+
+/// whether we already warned about `wg` failing since it last worked
+static FAILURE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// peer counters of the last successful query, reused while `wg` fails
+static LAST_READING: Mutex<Vec<(String, u64)>> = Mutex::new(Vec::new());
+
+/// Bytes sent to every peer of `interface` matching `wireguard_peers`, named
+/// `<interface>:<public key>`. While `wg` fails the last reading is returned, so no traffic
+/// gets counted in the meantime.
+pub fn fetch(interface: &str, config: &Config) -> Vec<(String, u64)> {
+    let mut last = LAST_READING.lock().unwrap();
+    match transfer(interface) {
+        Ok(peers) => {
+            FAILURE_WARNED.store(false, Ordering::SeqCst);
+            *last = peers
+                .into_iter()
+                .filter(|(peer, _)| {
+                    config.wireguard_peers.is_empty() || config.wireguard_peers.contains(peer)
+                })
+                .map(|(peer, tx)| (format!("{interface}:{peer}"), tx))
+                .collect();
+        }
+        Err(err) => {
+            if !FAILURE_WARNED.swap(true, Ordering::SeqCst) {
+                log(&format!(
+                    "Couldn't read the WireGuard peer statistics of {interface}: {err}"
+                ));
+            }
+        }
+    }
+    last.clone()
+}
+
+/// Parses the `<public key>\t<received>\t<sent>` lines printed by `wg show <interface> transfer`.
+pub fn transfer(interface: &str) -> Result<Vec<(String, u64)>, String> {
+    let output = Command::new("sudo")
+        .args(["wg", "show", interface, "transfer"])
+        .output()
+        .map_err(|err| format!("couldn't run wg: {err}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "wg exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields[..] {
+                [peer, _, tx] => tx
+                    .parse()
+                    .map(|tx| (peer.to_string(), tx))
+                    .map_err(|_| format!("unexpected line in the output of wg: {line}")),
+                _ => Err(format!("unexpected line in the output of wg: {line}")),
+            }
+        })
+        .collect()
+}